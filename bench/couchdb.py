@@ -0,0 +1,60 @@
+#!/usr/bin/env python3
+# Serves _all_docs for generated snippets with a fixed latency per request, used by pipeline.sh.
+# usage: couchdb.py PORT DOCUMENTS LATENCY_SECONDS
+import json
+import sys
+import time
+import urllib.parse
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+
+port, count, latency = int(sys.argv[1]), int(sys.argv[2]), float(sys.argv[3])
+
+docs = {}
+for i in range(count):
+    id = "s%06d" % i
+    docs[id] = {
+        "_id": id, "_rev": "1-x",
+        "created": "2020-01-01T00:00:00Z", "modified": "2020-01-02T00:00:00Z",
+        "language": "python", "title": "title %d" % i, "public": True, "owner": "anonymous",
+        "files": [{"name": "main.py", "content": "print(%d)" % i}],
+    }
+ids = sorted(docs)
+
+
+class Handler(BaseHTTPRequestHandler):
+    protocol_version = "HTTP/1.1"
+
+    def log_message(self, *args):
+        pass
+
+    def send(self, body):
+        data = json.dumps(body).encode()
+        self.send_response(200)
+        self.send_header("Content-Type", "application/json")
+        self.send_header("Content-Length", str(len(data)))
+        self.end_headers()
+        self.wfile.write(data)
+
+    def do_GET(self):
+        time.sleep(latency)
+        url = urllib.parse.urlparse(self.path)
+        query = dict(urllib.parse.parse_qsl(url.query))
+
+        if url.path == "/snippets":
+            return self.send({"db_name": "snippets", "doc_count": count, "update_seq": "0-g"})
+
+        selected = ids
+        if "startkey" in query:
+            start = json.loads(query["startkey"])
+            selected = [id for id in selected if id >= start]
+        offset = count - len(selected)
+        if "endkey" in query:
+            end = json.loads(query["endkey"])
+            selected = [id for id in selected if id <= end]
+        selected = selected[int(query.get("skip", 0)):][:int(query.get("limit", count))]
+
+        rows = [{"id": id, "key": id, "value": {"rev": "1-x"}, "doc": docs[id]} for id in selected]
+        self.send({"total_rows": count, "offset": offset, "rows": rows})
+
+
+ThreadingHTTPServer(("127.0.0.1", port), Handler).serve_forever()
@@ -0,0 +1,118 @@
+use crate::couchdb::CouchRow;
+use crate::error::Error;
+use crate::migrate;
+use crate::migrate::CodeFile;
+use crate::migrate::BulkResult;
+use crate::migrate::CodeSnippet;
+use crate::migrate::Profile;
+use postgres::types::ToSql;
+use std::collections::HashMap;
+
+// PostgreSQL allows at most 65535 parameters per statement
+pub const MAX_INSERT_BATCH_SIZE: u64 = 9000;
+
+// Inserts the snippets of a batch with multi-row INSERTs of `insert_batch_size` rows,
+// the returned ids are mapped back by slug to insert the files
+pub fn insert_documents(transaction: &mut postgres::Transaction, profiles: &HashMap<String, Profile>, rows: &[CouchRow], insert_batch_size: usize, upsert: bool) -> Result<BulkResult, Error> {
+    let documents = rows.iter()
+        .map(|row| {
+            migrate::parse_document(&row.doc)
+                .and_then(|doc| migrate::convert_document(&doc, profiles))
+                .map_err(|error| Error::Document{ id: row.id.clone(), error: Box::new(error) })
+        })
+        .collect::<Result<Vec<(CodeSnippet, Vec<CodeFile>)>, Error>>()?;
+
+    let mut snippet_ids = HashMap::new();
+    let mut updated_ids = Vec::new();
+
+    for chunk in documents.chunks(insert_batch_size) {
+        for row in insert_snippets(transaction, chunk, upsert)? {
+            let id: i64 = row.get(0);
+            let slug: String = row.get(1);
+            let inserted: bool = row.get(2);
+
+            if !inserted {
+                updated_ids.push(id);
+            }
+
+            snippet_ids.insert(slug, id);
+        }
+    }
+
+    if !updated_ids.is_empty() {
+        transaction.execute("DELETE FROM code_file WHERE code_snippet_id = ANY($1)", &[&updated_ids])?;
+    }
+
+    // Unchanged snippets are not returned by the upsert and keep their files
+    let files = documents.iter()
+        .filter_map(|(snippet, files)| snippet_ids.get(&snippet.slug).map(|id| (id, files)))
+        .flat_map(|(id, files)| files.iter().map(move |file| (id, file)))
+        .collect::<Vec<(&i64, &CodeFile)>>();
+
+    for chunk in files.chunks(insert_batch_size) {
+        insert_files(transaction, chunk)?;
+    }
+
+    Ok(BulkResult{
+        inserted: snippet_ids.len() - updated_ids.len(),
+        updated: updated_ids.len(),
+    })
+}
+
+fn insert_snippets(transaction: &mut postgres::Transaction, documents: &[(CodeSnippet, Vec<CodeFile>)], upsert: bool) -> Result<Vec<postgres::Row>, Error> {
+    let mut params: Vec<&(dyn ToSql + Sync)> = Vec::with_capacity(documents.len() * 7);
+
+    for (snippet, _) in documents {
+        params.push(&snippet.slug);
+        params.push(&snippet.language);
+        params.push(&snippet.title);
+        params.push(&snippet.public);
+        params.push(&snippet.user_id);
+        params.push(&snippet.created);
+        params.push(&snippet.modified);
+    }
+
+    let query = if upsert {
+        // Same conditions as the single row upsert, xmax = 0 means the row was inserted
+        format!("
+            INSERT INTO code_snippet (slug, language, title, public, user_id, created, modified) VALUES {}
+            ON CONFLICT (slug) DO UPDATE SET language = EXCLUDED.language, title = EXCLUDED.title, public = EXCLUDED.public, user_id = EXCLUDED.user_id, created = EXCLUDED.created, modified = EXCLUDED.modified
+            WHERE code_snippet.modified < EXCLUDED.modified
+            RETURNING id, slug, xmax = 0
+        ", values_placeholders(documents.len(), 7))
+    } else {
+        format!("INSERT INTO code_snippet (slug, language, title, public, user_id, created, modified) VALUES {} RETURNING id, slug, true", values_placeholders(documents.len(), 7))
+    };
+
+    Ok(transaction.query(query.as_str(), &params)?)
+}
+
+fn insert_files(transaction: &mut postgres::Transaction, files: &[(&i64, &CodeFile)]) -> Result<(), Error> {
+    let mut params: Vec<&(dyn ToSql + Sync)> = Vec::with_capacity(files.len() * 3);
+
+    for (id, file) in files {
+        params.push(*id);
+        params.push(&file.name);
+        params.push(&file.content);
+    }
+
+    let query = format!("INSERT INTO code_file (code_snippet_id, name, content) VALUES {}", values_placeholders(files.len(), 3));
+    transaction.execute(query.as_str(), &params)?;
+
+    Ok(())
+}
+
+// ($1, $2, $3), ($4, $5, $6), ...
+fn values_placeholders(rows: usize, columns: usize) -> String {
+    (0..rows)
+        .map(|row| {
+            let placeholders = (1..=columns)
+                .map(|column| format!("${}", row * columns + column))
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            format!("({})", placeholders)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,44 @@
+use crate::error::Error;
+
+// Used for a plain run, partitions have their own checkpoints named after the partition
+pub const CHECKPOINT_NAME: &str = "migrate";
+
+#[derive(Debug)]
+pub struct Checkpoint {
+    pub last_id: String,
+    pub rows_processed: i64,
+}
+
+pub fn create_table(client: &mut postgres::Client) -> Result<(), Error> {
+    client.batch_execute("
+        CREATE TABLE IF NOT EXISTS migration_checkpoint (
+            name text PRIMARY KEY,
+            last_id text NOT NULL,
+            rows_processed bigint NOT NULL,
+            updated timestamptz NOT NULL DEFAULT now()
+        )
+    ")?;
+
+    Ok(())
+}
+
+pub fn load(client: &mut postgres::Client, name: &str) -> Result<Option<Checkpoint>, Error> {
+    let checkpoint = client.query_opt("SELECT last_id, rows_processed FROM migration_checkpoint WHERE name = $1", &[&name])?
+        .map(|row| {
+            Checkpoint{
+                last_id: row.get(0),
+                rows_processed: row.get(1),
+            }
+        });
+
+    Ok(checkpoint)
+}
+
+pub fn save(transaction: &mut postgres::Transaction, name: &str, checkpoint: &Checkpoint) -> Result<(), Error> {
+    transaction.execute("
+        INSERT INTO migration_checkpoint (name, last_id, rows_processed, updated) VALUES ($1, $2, $3, now())
+        ON CONFLICT (name) DO UPDATE SET last_id = EXCLUDED.last_id, rows_processed = EXCLUDED.rows_processed, updated = EXCLUDED.updated
+    ", &[&name, &checkpoint.last_id, &checkpoint.rows_processed])?;
+
+    Ok(())
+}
@@ -0,0 +1,144 @@
+use crate::error::Error;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+// Opens a file for reading, decompressed while it is read when the path ends with .gz
+pub fn open(path: &str) -> Result<Box<dyn Read + Send>, Error> {
+    let file = fs::File::open(path)
+        .map_err(|error| Error::Io{ path: path.to_string(), error })?;
+
+    if path.ends_with(".gz") {
+        // Multi member, so files of concatenated gzip streams, i.e. written by pigz, are read to the end
+        Ok(Box::new(flate2::read::MultiGzDecoder::new(io::BufReader::new(file))))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+// Writes a file, compressed with gzip or zstd when the path ends with .gz or .zst.
+// finish has to be called at the end, otherwise the end of the compressed stream is missing.
+pub struct FileWriter {
+    path: String,
+    writer: io::BufWriter<Encoder>,
+}
+
+enum Encoder {
+    Plain(fs::File),
+    Gzip(flate2::write::GzEncoder<fs::File>),
+    Zstd(zstd::Encoder<'static, fs::File>),
+}
+
+impl FileWriter {
+    pub fn create(path: &str) -> Result<Self, Error> {
+        let file = fs::File::create(path)
+            .map_err(|error| Error::Io{ path: path.to_string(), error })?;
+
+        let encoder = if path.ends_with(".gz") {
+            Encoder::Gzip(flate2::write::GzEncoder::new(file, flate2::Compression::default()))
+        } else if path.ends_with(".zst") {
+            let encoder = zstd::Encoder::new(file, zstd::DEFAULT_COMPRESSION_LEVEL)
+                .map_err(|error| Error::Io{ path: path.to_string(), error })?;
+
+            Encoder::Zstd(encoder)
+        } else {
+            Encoder::Plain(file)
+        };
+
+        Ok(FileWriter{
+            path: path.to_string(),
+            writer: io::BufWriter::new(encoder),
+        })
+    }
+
+    pub fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
+        self.writer.write_all(buf)
+            .map_err(|error| Error::Io{ path: self.path.clone(), error })
+    }
+
+    pub fn finish(self) -> Result<(), Error> {
+        let path = self.path;
+
+        let encoder = self.writer.into_inner()
+            .map_err(|error| Error::Io{ path: path.clone(), error: error.into_error() })?;
+
+        encoder.finish()
+            .map_err(|error| Error::Io{ path, error })
+    }
+}
+
+impl Encoder {
+    // Writes the end of the compressed stream
+    fn finish(self) -> io::Result<()> {
+        let mut file = match self {
+            Encoder::Plain(file) => file,
+            Encoder::Gzip(encoder) => encoder.finish()?,
+            Encoder::Zstd(encoder) => encoder.finish()?,
+        };
+
+        file.flush()
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Plain(file) => file.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Plain(file) => file.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn write_file(path: &str, content: &[u8]) {
+        let mut writer = FileWriter::create(path).unwrap();
+        writer.write_all(content).unwrap();
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn reads_what_was_written() {
+        let content = "{\"_id\":\"snip1\"}\n".repeat(1000);
+
+        for extension in ["jsonl", "jsonl.gz"] {
+            let path = env::temp_dir().join(format!("compression-{}.{}", process::id(), extension));
+            let path = path.to_str().unwrap();
+
+            write_file(path, content.as_bytes());
+
+            let mut read = String::new();
+            open(path).unwrap().read_to_string(&mut read).unwrap();
+            fs::remove_file(path).unwrap();
+
+            assert_eq!(read, content, "{}", extension);
+        }
+    }
+
+    #[test]
+    fn writes_zstd() {
+        let path = env::temp_dir().join(format!("compression-{}.jsonl.zst", process::id()));
+        let path = path.to_str().unwrap();
+
+        write_file(path, b"{\"_id\":\"snip1\"}\n");
+
+        let decoded = zstd::decode_all(fs::File::open(path).unwrap()).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(decoded, b"{\"_id\":\"snip1\"}\n");
+    }
+}
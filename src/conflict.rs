@@ -0,0 +1,76 @@
+use crate::couchdb::CouchRow;
+use crate::error::Error;
+use crate::migrate::ConflictPolicy;
+use crate::source::SnippetSource;
+
+// Leaves out tombstones and rows that can't be snippets and applies the conflict policy, returns the
+// rows to migrate for a single CouchDB row. They keep the CouchDB id so the checkpoint still points into _all_docs.
+pub fn resolve_row(source: &dyn SnippetSource, row: CouchRow, policy: ConflictPolicy) -> Result<Vec<CouchRow>, Error> {
+    if row.is_deleted() {
+        debug!("Skipping deleted document '{}'", row.id);
+        return Ok(Vec::new());
+    }
+
+    if !row.doc.is_object() {
+        eprintln!("Skipping document '{}' with unexpected shape: {}", row.id, row.doc);
+        return Ok(Vec::new());
+    }
+
+    let conflicts = row.conflicts();
+
+    if conflicts.is_empty() {
+        return Ok(vec![row]);
+    }
+
+    match policy {
+        ConflictPolicy::Winner => {
+            eprintln!("Document '{}' has {} conflicting revisions, migrating the winning revision", row.id, conflicts.len());
+            Ok(vec![row])
+        }
+
+        ConflictPolicy::Skip => {
+            eprintln!("Skipping document '{}' with {} conflicting revisions", row.id, conflicts.len());
+            Ok(Vec::new())
+        }
+
+        ConflictPolicy::StoreRevisions => {
+            eprintln!("Document '{}' has {} conflicting revisions, migrating the winning revision and storing the others", row.id, conflicts.len());
+
+            let mut row = row;
+
+            // Only the winner becomes a snippet, the other revisions are kept out of code_snippet
+            for rev in conflicts {
+                let doc = source.get_revision(&row.id, &rev)?;
+                row.conflict_revisions.push((rev, doc));
+            }
+
+            Ok(vec![row])
+        }
+    }
+}
+
+pub fn create_table(client: &mut postgres::Client) -> Result<(), Error> {
+    client.batch_execute("
+        CREATE TABLE IF NOT EXISTS migration_conflict (
+            document_id text NOT NULL,
+            rev text NOT NULL,
+            document json NOT NULL,
+            stored_at timestamptz NOT NULL DEFAULT now(),
+            PRIMARY KEY (document_id, rev)
+        )
+    ")?;
+
+    Ok(())
+}
+
+// Saved in the transaction of the batch, a batch that is written again updates the stored revisions
+pub fn save_revisions(transaction: &mut postgres::Transaction, row: &CouchRow) -> Result<(), Error> {
+    for (rev, doc) in &row.conflict_revisions {
+        transaction.execute("
+            INSERT INTO migration_conflict (document_id, rev, document) VALUES ($1, $2, $3)
+            ON CONFLICT (document_id, rev) DO UPDATE SET document = EXCLUDED.document, stored_at = now()
+        ", &[&row.id, rev, doc])?;
+    }
+
+    Ok(())
+}
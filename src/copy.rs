@@ -0,0 +1,78 @@
+use crate::couchdb::CouchRow;
+use crate::error::Error;
+use crate::migrate;
+use crate::migrate::BulkResult;
+use crate::migrate::CodeFile;
+use crate::migrate::CodeSnippet;
+use crate::migrate::Profile;
+use postgres::binary_copy::BinaryCopyInWriter;
+use postgres::types::Type;
+use std::collections::HashMap;
+
+// Streams a whole batch through COPY. The snippet ids are taken from the sequence up front
+// so the files can reference them without a round trip per snippet.
+pub fn copy_documents(transaction: &mut postgres::Transaction, profiles: &HashMap<String, Profile>, rows: &[CouchRow]) -> Result<BulkResult, Error> {
+    let documents = rows.iter()
+        .map(|row| {
+            migrate::parse_document(&row.doc)
+                .and_then(|doc| migrate::convert_document(&doc, profiles))
+                .map_err(|error| Error::Document{ id: row.id.clone(), error: Box::new(error) })
+        })
+        .collect::<Result<Vec<(CodeSnippet, Vec<CodeFile>)>, Error>>()?;
+
+    let ids = transaction.query("SELECT nextval(pg_get_serial_sequence('code_snippet', 'id')) FROM generate_series(1, $1::bigint)", &[&(documents.len() as i64)])?
+        .iter()
+        .map(|row| row.get(0))
+        .collect::<Vec<i64>>();
+
+    let snippet_writer = transaction.copy_in("COPY code_snippet (id, slug, language, title, public, user_id, created, modified) FROM STDIN (FORMAT binary)")?;
+    let mut snippet_writer = BinaryCopyInWriter::new(snippet_writer, &[
+        Type::INT8,
+        Type::VARCHAR,
+        Type::VARCHAR,
+        Type::VARCHAR,
+        Type::BOOL,
+        Type::INT8,
+        Type::TIMESTAMPTZ,
+        Type::TIMESTAMPTZ,
+    ]);
+
+    for (id, (snippet, _)) in ids.iter().zip(&documents) {
+        snippet_writer.write(&[
+            id,
+            &snippet.slug,
+            &snippet.language,
+            &snippet.title,
+            &snippet.public,
+            &snippet.user_id,
+            &snippet.created,
+            &snippet.modified,
+        ])?;
+    }
+
+    snippet_writer.finish()?;
+
+    let file_writer = transaction.copy_in("COPY code_file (code_snippet_id, name, content) FROM STDIN (FORMAT binary)")?;
+    let mut file_writer = BinaryCopyInWriter::new(file_writer, &[
+        Type::INT8,
+        Type::VARCHAR,
+        Type::BYTEA,
+    ]);
+
+    for (id, (_, files)) in ids.iter().zip(&documents) {
+        for file in files {
+            file_writer.write(&[
+                id,
+                &file.name,
+                &file.content,
+            ])?;
+        }
+    }
+
+    file_writer.finish()?;
+
+    Ok(BulkResult{
+        inserted: documents.len(),
+        updated: 0,
+    })
+}
@@ -0,0 +1,809 @@
+use crate::error::Error;
+use crate::shutdown;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::io;
+use std::sync::Mutex;
+use std::thread;
+use std::time;
+
+// Upper bound for the delay between retries, however many attempts were made
+const MAX_RETRY_DELAY: time::Duration = time::Duration::from_secs(30);
+
+pub struct CouchDb {
+    base_url: String,
+    database: String,
+    auth: Auth,
+    headers: Vec<(String, String)>,
+    retry: RetryOptions,
+    session_cookie: Mutex<Option<String>>,
+}
+
+#[derive(Debug)]
+pub struct RetryOptions {
+    // Retries after the first attempt, 0 disables retrying
+    pub retries: u32,
+    // Delay before the first retry, doubled for every following one
+    pub initial_delay: time::Duration,
+    // Connect and read timeout of a single request
+    pub timeout: time::Duration,
+}
+
+impl RetryOptions {
+    // Exponential backoff with jitter, so workers that failed together don't retry together
+    fn delay(&self, attempt: u32) -> time::Duration {
+        let delay = self.initial_delay
+            .checked_mul(2u32.saturating_pow(attempt))
+            .unwrap_or(MAX_RETRY_DELAY)
+            .min(MAX_RETRY_DELAY);
+
+        let jitter = RandomState::new().hash_one(attempt) % 1000;
+
+        delay / 2 + delay / 2 * jitter as u32 / 1000
+    }
+}
+
+#[derive(Debug)]
+pub enum Auth {
+    None,
+    Basic { user: String, password: String },
+    // Logs in with POST /_session and sends the AuthSession cookie, logs in again when the session expires
+    Session { user: String, password: String },
+}
+
+impl CouchDb {
+    pub fn new(base_url: &str, database: &str, auth: Auth, headers: Vec<(String, String)>, retry: RetryOptions) -> Self {
+        CouchDb{
+            base_url: base_url.trim_end_matches('/').to_string(),
+            database: database.to_string(),
+            auth,
+            headers,
+            retry,
+            session_cookie: Mutex::new(None),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        // Database names may contain slashes which must be escaped in the path
+        format!("{}/{}{}", self.base_url, encode_path_segment(&self.database), path)
+    }
+
+    pub fn get_info(&self) -> Result<CouchDatabaseInfo, Error> {
+        self.get_json("", &[])
+    }
+
+    pub fn get_update_seq(&self) -> Result<String, Error> {
+        let info = self.get_info()?;
+
+        Ok(sequence_to_string(&info.update_seq))
+    }
+
+    pub fn get_changes(&self, since: &str, limit: u64, continuous: bool) -> Result<CouchChanges, Error> {
+        let limit = limit.to_string();
+
+        let mut query = vec![
+            ("since", since),
+            ("limit", &limit),
+            ("include_docs", "true"),
+        ];
+
+        // Blocks until there are new changes or the timeout (ms) expires, well before the read timeout
+        let longpoll_timeout = (self.retry.timeout / 2).min(time::Duration::from_secs(60)).as_millis().to_string();
+
+        if continuous {
+            query.push(("feed", "longpoll"));
+            query.push(("timeout", &longpoll_timeout));
+        }
+
+        self.get_json("/_changes", &query)
+    }
+
+    // Hands the documents after start_key to on_row while the response is parsed, so only one document
+    // is held in memory whatever the page size. Design documents are left out. The start key is requested
+    // again and dropped by id instead of using skip, so a page never loses a document when the start key
+    // was deleted in the meantime. A response that breaks off is requested again after the last row that
+    // was handed on, an error returned by on_row stops the page and is never retried.
+    pub fn stream_documents<F>(&self, start_key: Option<String>, end_key: Option<&str>, limit: u64, mut on_row: F) -> Result<CouchPageInfo, Error>
+        where F: FnMut(CouchRow) -> Result<(), Error>
+    {
+        let mut page = PageState{
+            start_key,
+            remaining: limit,
+            last_key: None,
+        };
+
+        let mut row_error = None;
+        let result = self.with_retries(|| self.stream_page(&mut page, end_key, &mut on_row, &mut row_error));
+
+        if let Some(error) = row_error {
+            return Err(error);
+        }
+
+        Ok(CouchPageInfo{
+            total_rows: result?,
+            last_key: page.last_key,
+        })
+    }
+
+    fn stream_page(&self, page: &mut PageState, end_key: Option<&str>, on_row: &mut dyn FnMut(CouchRow) -> Result<(), Error>, row_error: &mut Option<Error>) -> Result<u64, Error> {
+        // Continues after the rows that were handed on before the previous attempt broke off
+        let cursor = page.last_key.clone().or_else(|| page.start_key.clone());
+
+        let mut query = vec![
+            ("descending", "false".to_string()),
+            ("include_docs", "true".to_string()),
+            ("conflicts", "true".to_string()),
+        ];
+
+        match &cursor {
+            Some(cursor) => {
+                query.push(("startkey", serde_json::Value::from(cursor.as_str()).to_string()));
+                query.push(("limit", (page.remaining + 1).to_string()));
+            }
+
+            None => {
+                query.push(("limit", page.remaining.to_string()));
+            }
+        }
+
+        if let Some(end_key) = end_key {
+            query.push(("endkey", serde_json::Value::from(end_key).to_string()));
+        }
+
+        let query = query.iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect::<Vec<_>>();
+
+        let response = self.get_ok("/_all_docs", &query)?;
+        let url = response.get_url().to_string();
+        let mut first_row = true;
+
+        let mut handle_row = |row: CouchRow| {
+            if std::mem::replace(&mut first_row, false) {
+                if let Some(cursor) = &cursor {
+                    if &row.id == cursor {
+                        return Ok(());
+                    }
+
+                    debug!("Start key '{}' is gone, continuing with '{}'", cursor, row.id);
+                }
+            }
+
+            // The start key was gone, so there is one row more than needed
+            if page.remaining == 0 {
+                return Ok(());
+            }
+
+            let id = row.id.clone();
+
+            if !id.starts_with("_design/") {
+                on_row(row)?;
+            }
+
+            page.remaining -= 1;
+            page.last_key = Some(id);
+
+            Ok(())
+        };
+
+        let mut deserializer = serde_json::Deserializer::from_reader(response.into_reader());
+
+        let total_rows = serde::Deserializer::deserialize_map(&mut deserializer, PageVisitor{ on_row: &mut handle_row, row_error })
+            .and_then(|total_rows| deserializer.end().map(|_| total_rows))
+            .map_err(|error| Error::JsonDecode{ url, error })?;
+
+        Ok(total_rows)
+    }
+
+    pub fn get_revision(&self, id: &str, rev: &str) -> Result<serde_json::Value, Error> {
+        self.get_json(&format!("/{}", encode_path_segment(id)), &[("rev", rev)])
+    }
+
+    // Id of the document at the given position in _all_docs, used to split the key space
+    pub fn get_key_at(&self, position: u64) -> Result<Option<String>, Error> {
+        let response: CouchKeysResponse = self.get_json("/_all_docs", &[
+            ("skip", &position.to_string()),
+            ("limit", "1"),
+        ])?;
+
+        Ok(response.rows.into_iter().next().map(|row| row.id))
+    }
+
+    fn get_json<T: serde::de::DeserializeOwned>(&self, path: &str, query: &[(&str, &str)]) -> Result<T, Error> {
+        self.with_retries(|| {
+            let response = self.get_ok(path, query)?;
+            let url = response.get_url().to_string();
+
+            // Not into_json_deserialize, it turns a connection lost while reading into a decoding error
+            serde_json::from_reader(response.into_reader())
+                .map_err(|error| Error::JsonDecode{ url, error })
+        })
+    }
+
+    // Retries timeouts, connection errors and 5xx responses, other errors are returned right away
+    fn with_retries<T, R: FnMut() -> Result<T, Error>>(&self, mut request: R) -> Result<T, Error> {
+        let mut attempt = 0;
+
+        loop {
+            match request() {
+                Err(error) if error.is_retryable() && attempt < self.retry.retries && !shutdown::requested() => {
+                    let delay = self.retry.delay(attempt);
+                    attempt += 1;
+
+                    eprintln!("{}, retrying in {:.1}s ({} of {})", error, delay.as_secs_f64(), attempt, self.retry.retries);
+                    thread::sleep(delay);
+                }
+
+                result => return result,
+            }
+        }
+    }
+
+    fn get_ok(&self, path: &str, query: &[(&str, &str)]) -> Result<ureq::Response, Error> {
+        let mut response = self.get(path, query)?;
+
+        if response.status() == 401 {
+            if let Auth::Session { .. } = self.auth {
+                self.login()?;
+                response = self.get(path, query)?;
+            }
+        }
+
+        if !response.ok() {
+            let url = response.get_url().to_string();
+            let status = response.status();
+            let body = response.into_string().unwrap_or_default();
+            return Err(Error::CouchDbStatus{ url, status, body });
+        }
+
+        Ok(response)
+    }
+
+    fn get(&self, path: &str, query: &[(&str, &str)]) -> Result<ureq::Response, Error> {
+        if let Auth::Session { .. } = self.auth {
+            if self.session_cookie.lock().unwrap().is_none() {
+                self.login()?;
+            }
+        }
+
+        let mut request = ureq::get(&self.url(path));
+
+        for (name, value) in query {
+            request.query(name, value);
+        }
+
+        self.authorize(&mut request);
+        self.set_timeouts(&mut request);
+
+        debug!("GET {}", request.get_url());
+
+        // The signal that asks the run to stop interrupts reads with a timeout even with SA_RESTART,
+        // the request is sent again so the current page can still be finished
+        let response = loop {
+            let response = request.call();
+
+            match response.synthetic_error() {
+                Some(ureq::Error::Io(error)) if error.kind() == io::ErrorKind::Interrupted => continue,
+                _ => break response,
+            }
+        };
+
+        if let Some(error) = response.synthetic_error() {
+            return Err(request_error(request.get_url(), error));
+        }
+
+        // CouchDB sends a refreshed cookie when the session is about to expire
+        self.store_session_cookie(&response);
+
+        Ok(response)
+    }
+
+    fn authorize(&self, request: &mut ureq::Request) {
+        for (name, value) in &self.headers {
+            request.set(name, value);
+        }
+
+        match &self.auth {
+            Auth::None => (),
+
+            Auth::Basic { user, password } => {
+                request.auth(user, password);
+            }
+
+            Auth::Session { .. } => {
+                if let Some(cookie) = self.session_cookie.lock().unwrap().as_ref() {
+                    request.set("Cookie", cookie);
+                }
+            }
+        }
+    }
+
+    fn set_timeouts(&self, request: &mut ureq::Request) {
+        let timeout = self.retry.timeout.as_millis() as u64;
+
+        request.timeout_connect(timeout);
+        request.timeout_read(timeout);
+        request.timeout_write(timeout);
+    }
+
+    fn login(&self) -> Result<(), Error> {
+        let (user, password) = match &self.auth {
+            Auth::Session { user, password } => (user, password),
+            _ => return Ok(()),
+        };
+
+        let url = format!("{}/_session", self.base_url);
+        let mut request = ureq::post(&url);
+
+        for (name, value) in &self.headers {
+            request.set(name, value);
+        }
+
+        self.set_timeouts(&mut request);
+
+        debug!("POST {}", url);
+
+        let response = request.send_form(&[("name", user), ("password", password)]);
+
+        if let Some(error) = response.synthetic_error() {
+            return Err(request_error(&url, error));
+        }
+
+        if !response.ok() {
+            let status = response.status();
+            let body = response.into_string().unwrap_or_default();
+            return Err(Error::CouchDbStatus{ url, status, body });
+        }
+
+        if !self.store_session_cookie(&response) {
+            return Err(Error::CouchDbStatus{ url, status: response.status(), body: "no AuthSession cookie in response".to_string() });
+        }
+
+        Ok(())
+    }
+
+    fn store_session_cookie(&self, response: &ureq::Response) -> bool {
+        let cookie = response.all("set-cookie").into_iter()
+            .filter_map(|header| header.split(';').next())
+            .find(|cookie| cookie.starts_with("AuthSession="));
+
+        match cookie {
+            Some(cookie) => {
+                *self.session_cookie.lock().unwrap() = Some(cookie.to_string());
+                true
+            }
+
+            None => false,
+        }
+    }
+}
+
+// Timeouts, lost connections and failed lookups may go away on their own, a bad url or TLS setup won't
+fn request_error(url: &str, error: &ureq::Error) -> Error {
+    let retryable = matches!(error,
+        ureq::Error::DnsFailed(_)
+        | ureq::Error::ConnectionFailed(_)
+        | ureq::Error::Io(_)
+        | ureq::Error::BadStatus
+        | ureq::Error::BadHeader
+        | ureq::Error::ProxyConnect
+    );
+
+    Error::CouchDbRequest{ url: url.to_string(), error: error.to_string(), retryable }
+}
+
+// Percent-encodes everything but the unreserved characters, so ids with i.e. '/', '?' or '%' stay one segment of the path
+fn encode_path_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            byte => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+// Parses a "Name: value" header given on the command line
+pub fn parse_header(header: &str) -> Result<(String, String), String> {
+    match header.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        }
+
+        _ => Err(format!("expected 'Name: value', got '{}'", header)),
+    }
+}
+
+// CouchDB 1.x uses numeric sequences, 2.x and later opaque strings
+fn sequence_to_string(seq: &serde_json::Value) -> String {
+    match seq {
+        serde_json::Value::String(seq) => seq.clone(),
+        seq => seq.to_string(),
+    }
+}
+
+
+#[derive(Debug)]
+pub struct CouchPage {
+    pub total_rows: u64,
+    pub rows: Vec<CouchRow>,
+    // Id of the last row of the page including design documents, None when there was nothing after the start key
+    pub last_key: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct CouchPageInfo {
+    pub total_rows: u64,
+    pub last_key: Option<String>,
+}
+
+struct PageState {
+    start_key: Option<String>,
+    remaining: u64,
+    last_key: Option<String>,
+}
+
+// Reads an _all_docs response and hands every row to on_row as soon as it is parsed, returns total_rows
+struct PageVisitor<'a> {
+    on_row: &'a mut dyn FnMut(CouchRow) -> Result<(), Error>,
+    // serde errors can only carry a message, the error of on_row is kept here
+    row_error: &'a mut Option<Error>,
+}
+
+impl<'de, 'a> serde::de::Visitor<'de> for PageVisitor<'a> {
+    type Value = u64;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an _all_docs response")
+    }
+
+    fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> Result<u64, A::Error> {
+        let mut total_rows = 0;
+
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "total_rows" => total_rows = map.next_value()?,
+                "rows" => map.next_value_seed(RowsVisitor{ on_row: &mut *self.on_row, row_error: &mut *self.row_error })?,
+                _ => {
+                    map.next_value::<serde::de::IgnoredAny>()?;
+                }
+            }
+        }
+
+        Ok(total_rows)
+    }
+}
+
+struct RowsVisitor<'a> {
+    on_row: &'a mut dyn FnMut(CouchRow) -> Result<(), Error>,
+    row_error: &'a mut Option<Error>,
+}
+
+impl<'de, 'a> serde::de::DeserializeSeed<'de> for RowsVisitor<'a> {
+    type Value = ();
+
+    fn deserialize<D: serde::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, 'a> serde::de::Visitor<'de> for RowsVisitor<'a> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a list of rows")
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(row) = seq.next_element::<CouchRow>()? {
+            if let Err(error) = (self.on_row)(row) {
+                *self.row_error = Some(error);
+                return Err(serde::de::Error::custom("stopped after a row failed"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct CouchRow {
+    pub id: String,
+    #[serde(default)]
+    pub value: Option<CouchRowValue>,
+    // Kept as raw JSON so failed documents can be stored exactly as they are in CouchDB, null for deleted documents
+    #[serde(default)]
+    pub doc: serde_json::Value,
+    // Losing revisions fetched by --conflicts=store-revisions as (rev, document), stored next to the winner
+    #[serde(skip)]
+    pub conflict_revisions: Vec<(String, serde_json::Value)>,
+}
+
+impl CouchRow {
+    pub fn is_deleted(&self) -> bool {
+        self.value.as_ref().is_some_and(|value| value.deleted)
+            || self.doc.is_null()
+            || self.doc.get("_deleted") == Some(&serde_json::Value::Bool(true))
+    }
+
+    // Revisions that lost against the winning revision, only included with conflicts=true
+    pub fn conflicts(&self) -> Vec<String> {
+        match self.doc.get("_conflicts") {
+            Some(serde_json::Value::Array(revs)) => {
+                revs.iter()
+                    .filter_map(|rev| rev.as_str())
+                    .map(|rev| rev.to_string())
+                    .collect()
+            }
+
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct CouchRowValue {
+    pub rev: String,
+    #[serde(default)]
+    pub deleted: bool,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct CouchKeysResponse {
+    rows: Vec<CouchKeyRow>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct CouchKeyRow {
+    id: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct CouchDatabaseInfo {
+    pub doc_count: u64,
+    pub update_seq: serde_json::Value,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct CouchChanges {
+    pub results: Vec<CouchChange>,
+    pub last_seq: serde_json::Value,
+}
+
+impl CouchChanges {
+    pub fn last_seq(&self) -> String {
+        sequence_to_string(&self.last_seq)
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct CouchChange {
+    pub id: String,
+    #[serde(default)]
+    pub deleted: bool,
+    pub doc: Option<serde_json::Value>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct CouchDocument {
+    pub _id: String,
+    pub created: String,
+    pub modified: String,
+    pub language: String,
+    pub title: String,
+    pub public: bool,
+    pub owner: String,
+    pub files: Vec<File>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct File {
+    pub name: String,
+    #[serde(with = "serde_bytes")]
+    pub content: Vec<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_couchdb::FakeCouchDb;
+    use crate::fake_couchdb::Response;
+    use crate::fake_couchdb::Route;
+
+    const INFO: &str = include_str!("../tests/fixtures/changes/info.json");
+
+    // Reads pages of three documents like the driver does, continuing after the last key of every page
+    fn read_all(couchdb: &CouchDb) -> Vec<String> {
+        let mut ids = Vec::new();
+        let mut start_key = None;
+
+        loop {
+            let page = couchdb.stream_documents(start_key.clone(), None, 3, |row| {
+                ids.push(row.id);
+                Ok(())
+            }).unwrap();
+
+            match page.last_key {
+                Some(last_key) => start_key = Some(last_key),
+                None => return ids,
+            }
+        }
+    }
+
+    #[test]
+    fn continues_after_a_deleted_start_key() {
+        let fake = FakeCouchDb::start(vec![
+            Route::new("/snippets/_all_docs", &[("startkey", "%22s2%22")], include_str!("../tests/fixtures/all_docs/after_s2.json")),
+            Route::new("/snippets/_all_docs", &[("startkey", "%22s5%22")], include_str!("../tests/fixtures/all_docs/after_s5.json")),
+            Route::new("/snippets/_all_docs", &[("startkey", "%22s7%22")], include_str!("../tests/fixtures/all_docs/after_s7.json")),
+            Route::new("/snippets/_all_docs", &[], include_str!("../tests/fixtures/all_docs/first.json")),
+        ]);
+
+        // s2 was deleted between the first and the second page, s3 must not be skipped in its place
+        assert_eq!(read_all(&fake.couchdb()), ["s1", "s2", "s3", "s4", "s5", "s6", "s7"]);
+    }
+
+    #[test]
+    fn requests_one_row_more_after_a_start_key() {
+        let fake = FakeCouchDb::start(vec![
+            Route::new("/snippets/_all_docs", &[("startkey", "%22s2%22")], include_str!("../tests/fixtures/all_docs/after_s2.json")),
+            Route::new("/snippets/_all_docs", &[], include_str!("../tests/fixtures/all_docs/first.json")),
+        ]);
+
+        let couchdb = fake.couchdb();
+        couchdb.stream_documents(None, None, 3, |_| Ok(())).unwrap();
+        couchdb.stream_documents(Some("s2".to_string()), None, 3, |_| Ok(())).unwrap();
+
+        let requests = fake.requests();
+        assert!(requests[0].contains("limit=3") && !requests[0].contains("startkey"));
+        assert!(requests[1].contains("limit=4") && requests[1].contains("startkey=%22s2%22"));
+    }
+
+    #[test]
+    fn drops_the_start_row_by_id() {
+        let fake = FakeCouchDb::start(vec![
+            Route::new("/snippets/_all_docs", &[("startkey", "%22s5%22")], include_str!("../tests/fixtures/all_docs/after_s5.json")),
+            Route::new("/snippets/_all_docs", &[("startkey", "%22s2%22")], include_str!("../tests/fixtures/all_docs/after_s2.json")),
+        ]);
+
+        let couchdb = fake.couchdb();
+        let mut ids = Vec::new();
+
+        // The start row is there and dropped, the page ends early
+        let page = couchdb.stream_documents(Some("s5".to_string()), None, 3, |row| {
+            ids.push(row.id);
+            Ok(())
+        }).unwrap();
+
+        assert_eq!(ids, ["s6", "s7"]);
+        assert_eq!(page.last_key.as_deref(), Some("s7"));
+
+        // The start row is gone, the first row is kept and the extra row at the end is dropped instead
+        ids.clear();
+        let page = couchdb.stream_documents(Some("s2".to_string()), None, 3, |row| {
+            ids.push(row.id);
+            Ok(())
+        }).unwrap();
+
+        assert_eq!(ids, ["s3", "s4", "s5"]);
+        assert_eq!(page.last_key.as_deref(), Some("s5"));
+        assert_eq!(page.total_rows, 8);
+    }
+
+    #[test]
+    fn encodes_the_document_id_as_one_path_segment() {
+        let fake = FakeCouchDb::start(vec![
+            Route::new("/snippets/a%2Fb%3Fc%23d%25e%2Bf%20g%C3%A9", &[("rev", "2-b")], r#"{"_id":"a/b?c#d%e+f gé","_rev":"2-b"}"#),
+        ]);
+
+        let doc = fake.couchdb().get_revision("a/b?c#d%e+f gé", "2-b").unwrap();
+
+        assert_eq!(doc["_id"], "a/b?c#d%e+f gé");
+    }
+
+    #[test]
+    fn retries_transient_failures() {
+        let fake = FakeCouchDb::start(vec![
+            Route::responses("/snippets", &[], vec![
+                Response::Status(500, r#"{"error":"unknown_error"}"#),
+                Response::Status(408, r#"{"error":"timeout"}"#),
+                Response::Status(429, r#"{"error":"too_many_requests"}"#),
+                Response::Reset,
+                Response::Truncated(INFO, 20),
+                Response::Ok(INFO),
+            ]),
+        ]);
+
+        assert_eq!(fake.couchdb_with_retries(5).get_update_seq().unwrap(), "0-g1AAAAB");
+        assert_eq!(fake.requests().len(), 6);
+    }
+
+    #[test]
+    fn gives_up_after_the_last_retry() {
+        let fake = FakeCouchDb::start(vec![
+            Route::responses("/snippets", &[], vec![Response::Status(503, r#"{"error":"unavailable"}"#)]),
+        ]);
+
+        match fake.couchdb_with_retries(2).get_info() {
+            Err(Error::CouchDbStatus { status, .. }) => assert_eq!(status, 503),
+            result => panic!("expected a status error, got {:?}", result),
+        }
+
+        assert_eq!(fake.requests().len(), 3);
+    }
+
+    #[test]
+    fn fails_right_away_on_client_errors_and_malformed_json() {
+        for status in [400, 401, 404] {
+            let fake = FakeCouchDb::start(vec![
+                Route::responses("/snippets", &[], vec![Response::Status(status, r#"{"error":"bad_request"}"#), Response::Ok(INFO)]),
+            ]);
+
+            match fake.couchdb_with_retries(5).get_info() {
+                Err(Error::CouchDbStatus { status: returned, .. }) => assert_eq!(returned, status),
+                result => panic!("expected a status error, got {:?}", result),
+            }
+
+            assert_eq!(fake.requests().len(), 1);
+        }
+
+        let fake = FakeCouchDb::start(vec![
+            Route::responses("/snippets", &[], vec![Response::Ok(r#"{"update_seq": }"#), Response::Ok(INFO)]),
+        ]);
+
+        match fake.couchdb_with_retries(5).get_info() {
+            Err(error @ Error::JsonDecode { .. }) => assert!(!error.is_retryable()),
+            result => panic!("expected a decoding error, got {:?}", result),
+        }
+
+        assert_eq!(fake.requests().len(), 1);
+    }
+
+    #[test]
+    fn retried_page_continues_after_the_last_row_handed_on() {
+        let page = include_str!("../tests/fixtures/all_docs/after_s2.json");
+        let cut = page.find(r#"{"id":"s5""#).unwrap();
+
+        let fake = FakeCouchDb::start(vec![
+            Route::responses("/snippets/_all_docs", &[("startkey", "%22s2%22")], vec![Response::Truncated(page, cut)]),
+            Route::new("/snippets/_all_docs", &[("startkey", "%22s4%22")], include_str!("../tests/fixtures/all_docs/after_s4.json")),
+        ]);
+
+        let mut ids = Vec::new();
+
+        let page = fake.couchdb_with_retries(1).stream_documents(Some("s2".to_string()), None, 3, |row| {
+            ids.push(row.id);
+            Ok(())
+        }).unwrap();
+
+        assert_eq!(ids, ["s3", "s4", "s5"]);
+        assert_eq!(page.last_key.as_deref(), Some("s5"));
+
+        let requests = fake.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].contains("startkey=%22s4%22") && requests[1].contains("limit=2"));
+    }
+
+    #[test]
+    fn errors_of_the_row_handler_are_not_retried() {
+        let fake = FakeCouchDb::start(vec![
+            Route::new("/snippets/_all_docs", &[("startkey", "%22s2%22")], include_str!("../tests/fixtures/all_docs/after_s2.json")),
+        ]);
+
+        let result = fake.couchdb_with_retries(5).stream_documents(Some("s2".to_string()), None, 3, |row| {
+            Err(Error::Interrupted{ last_id: Some(row.id) })
+        });
+
+        match result {
+            Err(Error::Interrupted { last_id }) => assert_eq!(last_id.as_deref(), Some("s3")),
+            result => panic!("expected the error of the handler, got {:?}", result),
+        }
+
+        assert_eq!(fake.requests().len(), 1);
+    }
+}
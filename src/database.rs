@@ -0,0 +1,232 @@
+use crate::error::Error;
+use std::fs;
+use std::thread;
+use std::time;
+
+// Attempts after the first one before a transient error is given up on
+const MAX_RETRIES: u32 = 5;
+
+#[derive(Debug)]
+pub struct ConnectOptions {
+    pub url: Option<DatabaseUrl>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub database: Option<String>,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub ssl_mode: Option<SslMode>,
+    pub ca_file: Option<String>,
+}
+
+// The modes of libpq: prefer and require only encrypt the connection, verify-ca also checks that the certificate
+// is signed by a trusted CA and verify-full that it was issued for the host
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum SslMode {
+    Disable,
+    Prefer,
+    Require,
+    VerifyCa,
+    VerifyFull,
+}
+
+impl SslMode {
+    fn from_postgres(ssl_mode: postgres::config::SslMode) -> Self {
+        match ssl_mode {
+            postgres::config::SslMode::Disable => SslMode::Disable,
+            postgres::config::SslMode::Require => SslMode::Require,
+            _ => SslMode::Prefer,
+        }
+    }
+
+    // postgres only knows whether to use TLS, the certificate checks are set up on the connector
+    fn to_postgres(self) -> postgres::config::SslMode {
+        match self {
+            SslMode::Disable => postgres::config::SslMode::Disable,
+            SslMode::Prefer => postgres::config::SslMode::Prefer,
+            SslMode::Require | SslMode::VerifyCa | SslMode::VerifyFull => postgres::config::SslMode::Require,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DatabaseUrl {
+    config: postgres::Config,
+    ssl_mode: SslMode,
+}
+
+// Parses a connection string like libpq, postgres::Config rejects the verify modes so they are replaced before parsing
+pub fn parse_url(url: &str) -> Result<DatabaseUrl, String> {
+    let verify_mode = [("sslmode=verify-ca", SslMode::VerifyCa), ("sslmode=verify-full", SslMode::VerifyFull)]
+        .iter()
+        .find(|(param, _)| url.contains(param));
+
+    let url = match verify_mode {
+        Some((param, _)) => url.replace(param, "sslmode=require"),
+        None => url.to_string(),
+    };
+
+    let config = url.parse::<postgres::Config>()
+        .map_err(|error| error.to_string())?;
+
+    Ok(DatabaseUrl{
+        ssl_mode: verify_mode.map(|&(_, mode)| mode).unwrap_or_else(|| SslMode::from_postgres(config.get_ssl_mode())),
+        config,
+    })
+}
+
+pub fn connect(options: &ConnectOptions) -> Result<postgres::Client, Error> {
+    let (config, ssl_mode) = build_config(options);
+
+    if ssl_mode == SslMode::Disable {
+        return Ok(config.connect(postgres::NoTls)?);
+    }
+
+    let mut builder = native_tls::TlsConnector::builder();
+
+    // Without a CA bundle prefer and require don't check the certificate, like libpq
+    if options.ca_file.is_none() && (ssl_mode == SslMode::Prefer || ssl_mode == SslMode::Require) {
+        builder.danger_accept_invalid_certs(true);
+        builder.danger_accept_invalid_hostnames(true);
+    }
+
+    if ssl_mode == SslMode::VerifyCa {
+        builder.danger_accept_invalid_hostnames(true);
+    }
+
+    if let Some(path) = &options.ca_file {
+        let pem = fs::read(path)
+            .map_err(|error| Error::Io{ path: path.clone(), error })?;
+
+        let certificate = native_tls::Certificate::from_pem(&pem)
+            .map_err(Error::Tls)?;
+
+        builder.add_root_certificate(certificate);
+    }
+
+    let connector = builder.build().map_err(Error::Tls)?;
+
+    Ok(config.connect(postgres_native_tls::MakeTlsConnector::new(connector))?)
+}
+
+// Runs write again after transient errors, on a new connection when the old one was lost, so anything
+// tied to the connection like prepared statements has to be set up inside write. The connection may also have been lost
+// after the commit went through, before a retry already_committed checks for that and returns the result of the write.
+pub fn with_reconnect<C, A, W, T>(client: &mut postgres::Client, connect: C, mut already_committed: A, mut write: W) -> Result<T, Error>
+    where C: Fn() -> Result<postgres::Client, Error>,
+          A: FnMut(&mut postgres::Client) -> Result<Option<T>, Error>,
+          W: FnMut(&mut postgres::Client) -> Result<T, Error>
+{
+    let mut attempt = 0;
+
+    loop {
+        let result = reconnect(client, &connect)
+            .and_then(|()| if attempt > 0 { already_committed(client) } else { Ok(None) })
+            .and_then(|committed| match committed {
+                Some(result) => Ok(result),
+                None => write(client),
+            });
+
+        match result {
+            Err(error) if error.is_transient() && attempt < MAX_RETRIES => {
+                let delay = time::Duration::from_secs(1 << attempt);
+                attempt += 1;
+
+                eprintln!("{}, retrying in {}s ({} of {})", error, delay.as_secs(), attempt, MAX_RETRIES);
+                thread::sleep(delay);
+            }
+
+            result => return result,
+        }
+    }
+}
+
+fn reconnect<C: Fn() -> Result<postgres::Client, Error>>(client: &mut postgres::Client, connect: &C) -> Result<(), Error> {
+    if client.is_closed() {
+        *client = connect()?;
+    }
+
+    Ok(())
+}
+
+// Values from the connection url take precedence, the separate options only fill in what the url leaves out
+fn build_config(options: &ConnectOptions) -> (postgres::Config, SslMode) {
+    let (mut config, url_ssl_mode) = match &options.url {
+        Some(url) => (url.config.clone(), url.ssl_mode),
+        None => (postgres::Config::new(), SslMode::Disable),
+    };
+
+    if config.get_hosts().is_empty() {
+        config.host(options.host.as_deref().unwrap_or("localhost"));
+    }
+
+    if config.get_ports().is_empty() {
+        if let Some(port) = options.port {
+            config.port(port);
+        }
+    }
+
+    if config.get_dbname().is_none() {
+        if let Some(database) = &options.database {
+            config.dbname(database);
+        }
+    }
+
+    if config.get_user().is_none() {
+        if let Some(user) = &options.user {
+            config.user(user);
+        }
+    }
+
+    if config.get_password().is_none() {
+        if let Some(password) = &options.password {
+            config.password(password);
+        }
+    }
+
+    let ssl_mode = options.ssl_mode.unwrap_or(url_ssl_mode);
+    config.ssl_mode(ssl_mode.to_postgres());
+
+    (config, ssl_mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(url: Option<&str>, ssl_mode: Option<SslMode>) -> ConnectOptions {
+        ConnectOptions{
+            url: url.map(|url| parse_url(url).unwrap()),
+            host: None,
+            port: None,
+            database: None,
+            user: None,
+            password: None,
+            ssl_mode,
+            ca_file: None,
+        }
+    }
+
+    #[test]
+    fn parses_the_libpq_ssl_modes() {
+        assert_eq!(parse_url("postgresql://postgres@localhost/db").unwrap().ssl_mode, SslMode::Prefer);
+        assert_eq!(parse_url("postgresql://postgres@localhost/db?sslmode=disable").unwrap().ssl_mode, SslMode::Disable);
+        assert_eq!(parse_url("postgresql://postgres@localhost/db?sslmode=verify-full&connect_timeout=5").unwrap().ssl_mode, SslMode::VerifyFull);
+        assert_eq!(parse_url("host=localhost sslmode=verify-ca user=postgres").unwrap().ssl_mode, SslMode::VerifyCa);
+        assert!(parse_url("postgresql://postgres@localhost/db?sslmode=sometimes").is_err());
+    }
+
+    #[test]
+    fn the_ssl_mode_option_overrides_the_url() {
+        let (config, ssl_mode) = build_config(&options(Some("postgresql://postgres@db.example.com/db?sslmode=verify-full"), None));
+        assert_eq!(ssl_mode, SslMode::VerifyFull);
+        assert_eq!(config.get_ssl_mode(), postgres::config::SslMode::Require);
+
+        let (config, ssl_mode) = build_config(&options(Some("postgresql://postgres@db.example.com/db"), Some(SslMode::Disable)));
+        assert_eq!(ssl_mode, SslMode::Disable);
+        assert_eq!(config.get_ssl_mode(), postgres::config::SslMode::Disable);
+
+        let (config, ssl_mode) = build_config(&options(None, None));
+        assert_eq!(ssl_mode, SslMode::Disable);
+        assert_eq!(config.get_hosts(), [postgres::config::Host::Tcp("localhost".to_string())]);
+    }
+}
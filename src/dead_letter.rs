@@ -0,0 +1,273 @@
+use crate::error::Error;
+use crate::migrate;
+use crate::migrate::Profile;
+use std::collections::HashMap;
+use std::fs;
+use std::io::BufRead;
+use std::io::Write;
+use std::sync::Mutex;
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct FailedDocument {
+    // Primary key in the migration_failure table, not part of the JSONL format
+    #[serde(skip)]
+    pub row_id: Option<i64>,
+    pub id: String,
+    pub kind: String,
+    pub message: String,
+    pub document: serde_json::Value,
+    pub failed_at: String,
+}
+
+impl FailedDocument {
+    pub fn new(id: &str, document: &serde_json::Value, error: &Error) -> Self {
+        FailedDocument{
+            row_id: None,
+            id: id.to_string(),
+            kind: error.kind().to_string(),
+            message: error.to_string(),
+            document: document.clone(),
+            failed_at: chrono::Utc::now().to_rfc3339(),
+        }
+    }
+}
+
+// Shared by all writer workers, the file is locked so lines from different workers don't interleave
+pub struct DeadLetter {
+    file: Option<(String, Mutex<fs::File>)>,
+    table: bool,
+}
+
+impl DeadLetter {
+    pub fn new(file_path: Option<&str>, table: bool) -> Result<Self, Error> {
+        let file = match file_path {
+            Some(path) => {
+                let file = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|error| Error::Io{ path: path.to_string(), error })?;
+
+                Some((path.to_string(), Mutex::new(file)))
+            }
+
+            None => None,
+        };
+
+        Ok(DeadLetter{ file, table })
+    }
+
+    // Collects the failures of one batch transaction
+    pub fn batch(&self) -> DeadLetterBatch<'_> {
+        DeadLetterBatch{ dead_letter: self, failures: Vec::new() }
+    }
+
+    // Appends to the file right away, for the outputs other than PostgreSQL which have no transaction to wait for
+    pub fn append(&self, failure: &FailedDocument) -> Result<(), Error> {
+        if let Some((path, file)) = &self.file {
+            append_line(path, &mut file.lock().unwrap(), failure)?;
+        }
+
+        Ok(())
+    }
+}
+
+// The table rows are written in the batch transaction and the file lines are held back until it committed,
+// so a batch that is rolled back and written again doesn't leave its failures in the file twice
+pub struct DeadLetterBatch<'a> {
+    dead_letter: &'a DeadLetter,
+    failures: Vec<FailedDocument>,
+}
+
+impl<'a> DeadLetterBatch<'a> {
+    pub fn record(&mut self, transaction: &mut postgres::Transaction, failure: FailedDocument) -> Result<(), Error> {
+        if self.dead_letter.table {
+            insert_failure(transaction, &failure)?;
+        }
+
+        if self.dead_letter.file.is_some() {
+            self.failures.push(failure);
+        }
+
+        Ok(())
+    }
+
+    // Called after the batch transaction committed
+    pub fn commit(self) -> Result<(), Error> {
+        for failure in &self.failures {
+            self.dead_letter.append(failure)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+// Failed documents are retried in a single transaction, the ones that still fail stay in the dead letter
+pub fn replay(client: &mut postgres::Client, profiles: &HashMap<String, Profile>, upsert: bool, dead_letter_file: Option<&str>) -> Result<(), Error> {
+    let failures = match dead_letter_file {
+        Some(path) => read_file(path)?,
+        None => load_table(client)?,
+    };
+
+    let statements = migrate::prepare_statements(client, upsert)?;
+    let mut transaction = client.transaction()?;
+    let mut remaining = Vec::new();
+    let mut replayed_count = 0;
+
+    info!("Replaying {} failed documents", failures.len());
+
+    for failure in failures {
+        let mut savepoint = transaction.savepoint("document")?;
+        let result = migrate::parse_document(&failure.document)
+            .and_then(|doc| migrate::insert_document(&mut savepoint, &statements, profiles, &doc));
+
+        match result {
+            Ok(_) => {
+                savepoint.commit()?;
+
+                if dead_letter_file.is_none() {
+                    delete_from_table(&mut transaction, &failure)?;
+                }
+
+                replayed_count += 1;
+            }
+
+            Err(error) if error.is_document_error() => {
+                savepoint.rollback()?;
+                eprintln!("Document '{}' still fails: {}", failure.id, error);
+
+                let failure = FailedDocument{
+                    row_id: failure.row_id,
+                    ..FailedDocument::new(&failure.id, &failure.document, &error)
+                };
+
+                if dead_letter_file.is_none() {
+                    update_in_table(&mut transaction, &failure)?;
+                }
+
+                remaining.push(failure);
+            }
+
+            Err(error) => {
+                return Err(Error::Document{ id: failure.id, error: Box::new(error) });
+            }
+        }
+    }
+
+    transaction.commit()?;
+
+    if let Some(path) = dead_letter_file {
+        write_file(path, &remaining)?;
+    }
+
+    info!("Replayed {}, still failing {}", replayed_count, remaining.len());
+
+    Ok(())
+}
+
+
+pub fn create_table(client: &mut postgres::Client) -> Result<(), Error> {
+    // json rather than jsonb since jsonb can't store \u0000 which some documents contain
+    client.batch_execute("
+        CREATE TABLE IF NOT EXISTS migration_failure (
+            id bigserial PRIMARY KEY,
+            document_id text NOT NULL,
+            error_kind text NOT NULL,
+            error_message text NOT NULL,
+            document json NOT NULL,
+            failed_at timestamptz NOT NULL DEFAULT now()
+        )
+    ")?;
+
+    Ok(())
+}
+
+fn insert_failure(transaction: &mut postgres::Transaction, failure: &FailedDocument) -> Result<(), Error> {
+    transaction.execute(
+        "INSERT INTO migration_failure (document_id, error_kind, error_message, document) VALUES ($1, $2, $3, $4)",
+        &[&failure.id, &failure.kind, &failure.message, &failure.document],
+    )?;
+
+    Ok(())
+}
+
+fn load_table(client: &mut postgres::Client) -> Result<Vec<FailedDocument>, Error> {
+    let failures = client.query("SELECT id, document_id, error_kind, error_message, document, failed_at FROM migration_failure ORDER BY id", &[])?
+        .iter()
+        .map(|row| {
+            let failed_at: chrono::DateTime<chrono::Utc> = row.get(5);
+
+            FailedDocument{
+                row_id: row.get(0),
+                id: row.get(1),
+                kind: row.get(2),
+                message: row.get(3),
+                document: row.get(4),
+                failed_at: failed_at.to_rfc3339(),
+            }
+        })
+        .collect();
+
+    Ok(failures)
+}
+
+fn delete_from_table(transaction: &mut postgres::Transaction, failure: &FailedDocument) -> Result<(), Error> {
+    transaction.execute("DELETE FROM migration_failure WHERE id = $1", &[&failure.row_id])?;
+    Ok(())
+}
+
+fn update_in_table(transaction: &mut postgres::Transaction, failure: &FailedDocument) -> Result<(), Error> {
+    transaction.execute(
+        "UPDATE migration_failure SET error_kind = $2, error_message = $3, failed_at = now() WHERE id = $1",
+        &[&failure.row_id, &failure.kind, &failure.message],
+    )?;
+
+    Ok(())
+}
+
+
+fn read_file(path: &str) -> Result<Vec<FailedDocument>, Error> {
+    let file = fs::File::open(path)
+        .map_err(|error| Error::Io{ path: path.to_string(), error })?;
+
+    let mut failures = Vec::new();
+
+    for line in std::io::BufReader::new(file).lines() {
+        let line = line.map_err(|error| Error::Io{ path: path.to_string(), error })?;
+
+        if !line.trim().is_empty() {
+            let failure = serde_json::from_str(&line)
+                .map_err(|error| Error::FileDecode{ path: path.to_string(), error })?;
+
+            failures.push(failure);
+        }
+    }
+
+    Ok(failures)
+}
+
+
+// Writes to a temporary file first so the dead letters are never lost halfway through
+fn write_file(path: &str, failures: &[FailedDocument]) -> Result<(), Error> {
+    let tmp_path = format!("{}.tmp", path);
+
+    let mut file = fs::File::create(&tmp_path)
+        .map_err(|error| Error::Io{ path: tmp_path.clone(), error })?;
+
+    for failure in failures {
+        append_line(&tmp_path, &mut file, failure)?;
+    }
+
+    fs::rename(&tmp_path, path)
+        .map_err(|error| Error::Io{ path: path.to_string(), error })
+}
+
+fn append_line(path: &str, file: &mut fs::File, failure: &FailedDocument) -> Result<(), Error> {
+    let mut line = serde_json::to_vec(failure)
+        .map_err(|error| Error::Io{ path: path.to_string(), error: error.into() })?;
+    line.push(b'\n');
+
+    file.write_all(&line)
+        .map_err(|error| Error::Io{ path: path.to_string(), error })
+}
@@ -0,0 +1,344 @@
+use crate::conflict;
+use crate::couchdb::CouchRow;
+use crate::error::Error;
+use crate::migrate::ConflictPolicy;
+use crate::shutdown;
+use crate::source::SnippetSource;
+use std::time;
+
+pub struct Progress {
+    // Rows processed before this run, i.e. when resuming from a checkpoint
+    pub initial_rows_processed: usize,
+    pub rows_processed: usize,
+    pub total_rows: u64,
+    pub batches: u64,
+    pub elapsed: time::Duration,
+}
+
+impl Progress {
+    // Documents per second in this run, documents from before a resume are not counted
+    pub fn rate(&self) -> f64 {
+        (self.rows_processed - self.initial_rows_processed) as f64 / self.elapsed.as_secs_f64().max(0.001)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    Exhausted,
+    Limit,
+    Shutdown,
+}
+
+#[derive(Debug, Clone)]
+pub struct Position {
+    cursor: Option<String>,
+    rows_processed: usize,
+    remaining: Option<usize>,
+    batches: u64,
+    stop_reason: Option<StopReason>,
+}
+
+// Walks _all_docs page by page, keeping the cursor and counters between batches
+pub struct Driver<'a> {
+    source: &'a dyn SnippetSource,
+    batch_size: u64,
+    end_key: Option<String>,
+    cursor: Option<String>,
+    initial_rows_processed: usize,
+    rows_processed: usize,
+    remaining: Option<usize>,
+    conflict_policy: ConflictPolicy,
+    total_rows: u64,
+    batches: u64,
+    started: time::Instant,
+    stop_reason: Option<StopReason>,
+    on_progress: Box<dyn FnMut(&Progress) + 'a>,
+}
+
+impl<'a> Driver<'a> {
+    pub fn new(source: &'a dyn SnippetSource, batch_size: u64, end_key: Option<String>, start_key: Option<String>, rows_processed: usize, limit: Option<usize>) -> Self {
+        Driver{
+            source,
+            batch_size,
+            end_key,
+            cursor: start_key,
+            initial_rows_processed: rows_processed,
+            rows_processed,
+            remaining: limit,
+            conflict_policy: ConflictPolicy::Winner,
+            total_rows: 0,
+            batches: 0,
+            started: time::Instant::now(),
+            stop_reason: None,
+            on_progress: Box::new(|_| ()),
+        }
+    }
+
+    // Called by report_progress, after the caller is done with a batch
+    pub fn on_progress<F: FnMut(&Progress) + 'a>(mut self, on_progress: F) -> Self {
+        self.on_progress = Box::new(on_progress);
+        self
+    }
+
+    pub fn conflict_policy(mut self, conflict_policy: ConflictPolicy) -> Self {
+        self.conflict_policy = conflict_policy;
+        self
+    }
+
+    // Fetches the page after the cursor, returns None when there is nothing left to process
+    pub fn next_batch(&mut self) -> Result<Option<Vec<CouchRow>>, Error> {
+        let mut rows = Vec::new();
+
+        let more = self.stream_batch(|row| {
+            rows.push(row);
+            Ok(())
+        })?;
+
+        Ok(if more { Some(rows) } else { None })
+    }
+
+    // Hands the rows of the page after the cursor to on_row while the page is parsed, returns false when
+    // there is nothing left to process. The cursor and counters only move once the whole page went through.
+    pub fn stream_batch<F: FnMut(CouchRow) -> Result<(), Error>>(&mut self, mut on_row: F) -> Result<bool, Error> {
+        if self.stop_reason.is_some() {
+            return Ok(false);
+        }
+
+        let limit = match self.remaining {
+            Some(remaining) => self.batch_size.min(remaining as u64),
+            None => self.batch_size,
+        };
+
+        if limit == 0 {
+            info!("Stopped after reaching the limit, processed {}", self.rows_processed);
+            self.stop_reason = Some(StopReason::Limit);
+            return Ok(false);
+        }
+
+        if shutdown::requested() {
+            self.stop_reason = Some(StopReason::Shutdown);
+            return Ok(false);
+        }
+
+        let source = self.source;
+        let conflict_policy = self.conflict_policy;
+        let mut cursor = self.cursor.clone();
+
+        // Counted before resolving, rows_processed is the number of CouchDB documents
+        let mut documents_count = 0;
+
+        loop {
+            let page = source.stream_documents(cursor.clone(), self.end_key.as_deref(), limit, &mut |row| {
+                documents_count += 1;
+
+                for row in conflict::resolve_row(source, row, conflict_policy)? {
+                    on_row(row)?;
+                }
+
+                Ok(())
+            })?;
+
+            self.total_rows = page.total_rows;
+
+            match page.last_key {
+                // A page with only design documents, continue after them
+                Some(last_key) if documents_count == 0 => cursor = Some(last_key),
+
+                Some(last_key) => {
+                    cursor = Some(last_key);
+                    break;
+                }
+
+                None => {
+                    self.stop_reason = Some(StopReason::Exhausted);
+                    return Ok(false);
+                }
+            }
+        }
+
+        self.cursor = cursor;
+        self.rows_processed += documents_count;
+        self.remaining = self.remaining.map(|remaining| remaining - documents_count);
+        self.batches += 1;
+
+        Ok(true)
+    }
+
+    // Position to go back to when a batch has to be written again
+    pub fn position(&self) -> Position {
+        Position{
+            cursor: self.cursor.clone(),
+            rows_processed: self.rows_processed,
+            remaining: self.remaining,
+            batches: self.batches,
+            stop_reason: self.stop_reason,
+        }
+    }
+
+    pub fn rewind(&mut self, position: &Position) {
+        self.cursor = position.cursor.clone();
+        self.rows_processed = position.rows_processed;
+        self.remaining = position.remaining;
+        self.batches = position.batches;
+        self.stop_reason = position.stop_reason;
+    }
+
+    pub fn report_progress(&mut self) {
+        let progress = self.progress();
+        (self.on_progress)(&progress);
+    }
+
+    pub fn progress(&self) -> Progress {
+        Progress{
+            initial_rows_processed: self.initial_rows_processed,
+            rows_processed: self.rows_processed,
+            total_rows: self.total_rows,
+            batches: self.batches,
+            elapsed: self.started.elapsed(),
+        }
+    }
+
+    pub fn rows_processed(&self) -> usize {
+        self.rows_processed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::tests::document;
+    use crate::source::tests::row;
+    use crate::source::MemorySource;
+
+    fn source() -> MemorySource {
+        MemorySource::new((1..=7).map(|i| document(&format!("s{}", i))).collect())
+    }
+
+    fn ids(rows: &[CouchRow]) -> Vec<&str> {
+        rows.iter().map(|row| row.id.as_str()).collect()
+    }
+
+    #[test]
+    fn walks_the_pages() {
+        let source = source();
+        let mut driver = Driver::new(&source, 3, None, None, 0, None);
+
+        assert_eq!(ids(&driver.next_batch().unwrap().unwrap()), ["s1", "s2", "s3"]);
+        assert_eq!(ids(&driver.next_batch().unwrap().unwrap()), ["s4", "s5", "s6"]);
+        assert_eq!(ids(&driver.next_batch().unwrap().unwrap()), ["s7"]);
+        assert!(driver.next_batch().unwrap().is_none());
+
+        assert_eq!(driver.rows_processed(), 7);
+        assert_eq!(driver.progress().batches, 3);
+        assert_eq!(driver.progress().total_rows, 7);
+    }
+
+    #[test]
+    fn continues_after_the_start_key_up_to_the_end_key() {
+        let source = source();
+
+        // Resuming from a checkpoint counts the rows processed before
+        let mut driver = Driver::new(&source, 2, Some("s5".to_string()), Some("s2".to_string()), 2, None);
+
+        assert_eq!(ids(&driver.next_batch().unwrap().unwrap()), ["s3", "s4"]);
+        assert_eq!(ids(&driver.next_batch().unwrap().unwrap()), ["s5"]);
+        assert!(driver.next_batch().unwrap().is_none());
+        assert_eq!(driver.rows_processed(), 5);
+    }
+
+    #[test]
+    fn stops_at_the_limit() {
+        let source = source();
+        let mut driver = Driver::new(&source, 3, None, None, 0, Some(4));
+
+        assert_eq!(ids(&driver.next_batch().unwrap().unwrap()), ["s1", "s2", "s3"]);
+        assert_eq!(ids(&driver.next_batch().unwrap().unwrap()), ["s4"]);
+        assert!(driver.next_batch().unwrap().is_none());
+        assert_eq!(driver.rows_processed(), 4);
+
+        let mut driver = Driver::new(&source, 3, None, None, 0, Some(0));
+        assert!(driver.next_batch().unwrap().is_none());
+    }
+
+    #[test]
+    fn skips_pages_with_only_design_documents() {
+        let source = MemorySource::new(vec![
+            row("_design/a", serde_json::json!({"_id": "_design/a"})),
+            row("_design/b", serde_json::json!({"_id": "_design/b"})),
+            document("s1"),
+        ]);
+        let mut driver = Driver::new(&source, 2, None, None, 0, None);
+
+        assert_eq!(ids(&driver.next_batch().unwrap().unwrap()), ["s1"]);
+        assert!(driver.next_batch().unwrap().is_none());
+        assert_eq!(driver.rows_processed(), 1);
+    }
+
+    #[test]
+    fn rewinds_to_a_position() {
+        let source = source();
+        let mut driver = Driver::new(&source, 3, None, None, 0, Some(5));
+
+        driver.next_batch().unwrap();
+        let position = driver.position();
+
+        assert_eq!(ids(&driver.next_batch().unwrap().unwrap()), ["s4", "s5"]);
+        assert!(driver.next_batch().unwrap().is_none());
+
+        driver.rewind(&position);
+
+        assert_eq!(driver.rows_processed(), 3);
+        assert_eq!(ids(&driver.next_batch().unwrap().unwrap()), ["s4", "s5"]);
+        assert!(driver.next_batch().unwrap().is_none());
+    }
+
+    fn conflicting_source() -> MemorySource {
+        let mut conflicted = document("s2");
+        conflicted.doc["_conflicts"] = serde_json::json!(["2-b", "2-c"]);
+
+        let mut deleted = row("s4", serde_json::Value::Null);
+        deleted.value = Some(crate::couchdb::CouchRowValue{ rev: "3-d".to_string(), deleted: true });
+
+        MemorySource::new(vec![document("s1"), conflicted, row("s3", serde_json::json!("not an object")), deleted, document("s5")])
+            .revision("s2", "2-b", serde_json::json!({"_id": "s2", "title": "b"}))
+            .revision("s2", "2-c", serde_json::json!({"_id": "s2", "title": "c"}))
+    }
+
+    #[test]
+    fn leaves_out_deleted_and_unexpected_documents() {
+        let source = conflicting_source();
+        let mut driver = Driver::new(&source, 10, None, None, 0, None);
+
+        assert_eq!(ids(&driver.next_batch().unwrap().unwrap()), ["s1", "s2", "s5"]);
+
+        // Left out rows still count as processed
+        assert_eq!(driver.rows_processed(), 5);
+    }
+
+    #[test]
+    fn applies_the_conflict_policy() {
+        let source = conflicting_source();
+
+        let rows = Driver::new(&source, 10, None, None, 0, None)
+            .conflict_policy(ConflictPolicy::Winner)
+            .next_batch().unwrap().unwrap();
+        assert_eq!(ids(&rows), ["s1", "s2", "s5"]);
+        assert!(rows[1].conflict_revisions.is_empty());
+
+        let rows = Driver::new(&source, 10, None, None, 0, None)
+            .conflict_policy(ConflictPolicy::Skip)
+            .next_batch().unwrap().unwrap();
+        assert_eq!(ids(&rows), ["s1", "s5"]);
+
+        // The losing revisions stay with the winner instead of becoming rows of their own
+        let rows = Driver::new(&source, 10, None, None, 0, None)
+            .conflict_policy(ConflictPolicy::StoreRevisions)
+            .next_batch().unwrap().unwrap();
+        assert_eq!(ids(&rows), ["s1", "s2", "s5"]);
+
+        let revisions = rows[1].conflict_revisions.iter()
+            .map(|(rev, doc)| (rev.as_str(), doc["title"].as_str().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(revisions, [("2-b", "b"), ("2-c", "c")]);
+    }
+}
@@ -0,0 +1,103 @@
+use crate::couchdb::CouchDocument;
+use crate::driver::Driver;
+use crate::error::Error;
+use crate::language::parse_language;
+use crate::migrate;
+use crate::migrate::MigrateOptions;
+use crate::migrate::Profile;
+use crate::source::SnippetSource;
+use std::collections::HashMap;
+
+const ANONYMOUS_OWNER: &str = "anonymous";
+
+#[derive(Debug, Default)]
+pub struct DryRunReport {
+    documents: usize,
+    valid: usize,
+    invalid_documents: Vec<String>,
+    invalid_timestamps: Vec<String>,
+    unknown_languages: HashMap<String, usize>,
+    unknown_owners: Vec<String>,
+    anonymous: usize,
+    nul_bytes: Vec<String>,
+}
+
+pub fn dry_run_loop(source: &dyn SnippetSource, profiles: &HashMap<String, Profile>, options: &MigrateOptions, start_key: Option<String>, limit: Option<usize>, report: &mut DryRunReport) -> Result<(), Error> {
+    let mut driver = Driver::new(source, options.batch_size, options.end_key.clone(), start_key, 0, limit)
+        .conflict_policy(options.conflict_policy)
+        .on_progress(|progress| info!("Checked {} of {}", progress.rows_processed, progress.total_rows));
+
+    while let Some(rows) = driver.next_batch()? {
+        for row in &rows {
+            match migrate::parse_document(&row.doc) {
+                Ok(doc) => check_document(&doc, profiles, report),
+                Err(_) => {
+                    report.documents += 1;
+                    report.invalid_documents.push(row.id.clone());
+                }
+            }
+        }
+
+        driver.report_progress();
+    }
+
+    Ok(())
+}
+
+fn check_document(doc: &CouchDocument, profiles: &HashMap<String, Profile>, report: &mut DryRunReport) {
+    report.documents += 1;
+
+    if parse_language(&doc.language).is_none() {
+        *report.unknown_languages.entry(doc.language.clone()).or_insert(0) += 1;
+    }
+
+    if doc.owner == ANONYMOUS_OWNER {
+        report.anonymous += 1;
+    } else if !profiles.contains_key(&doc.owner) {
+        report.unknown_owners.push(doc._id.clone());
+    }
+
+    if doc.title.contains('\0') || doc.files.iter().any(|file| file.name.contains('\0')) {
+        report.nul_bytes.push(doc._id.clone());
+    }
+
+    match migrate::convert_document(doc, profiles) {
+        Ok(_) => report.valid += 1,
+        Err(_) => report.invalid_timestamps.push(doc._id.clone()),
+    }
+}
+
+pub fn print_report(report: &DryRunReport) {
+    let mut unknown_languages = report.unknown_languages.iter().collect::<Vec<_>>();
+    unknown_languages.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+    println!();
+    println!("Dry run summary (nothing was written)");
+    println!("  Documents:          {}", report.documents);
+    println!("  Valid:              {}", report.valid);
+    println!("  Invalid documents:  {}{}", report.invalid_documents.len(), format_examples(&report.invalid_documents));
+    println!("  Invalid timestamps: {}{}", report.invalid_timestamps.len(), format_examples(&report.invalid_timestamps));
+    println!("  Unknown languages:  {}", unknown_languages.iter().map(|(_, count)| *count).sum::<usize>());
+
+    for (language, count) in unknown_languages {
+        println!("    {:?}: {}", language, count);
+    }
+
+    println!("  Unknown owners:     {}{}", report.unknown_owners.len(), format_examples(&report.unknown_owners));
+    println!("  Anonymous owners:   {}", report.anonymous);
+    println!("  NUL bytes:          {}{}", report.nul_bytes.len(), format_examples(&report.nul_bytes));
+}
+
+fn format_examples(ids: &[String]) -> String {
+    if ids.is_empty() {
+        return String::new();
+    }
+
+    let examples = ids.iter().take(10).map(|id| id.as_str()).collect::<Vec<_>>().join(", ");
+
+    if ids.len() > 10 {
+        format!(" ({}, ...)", examples)
+    } else {
+        format!(" ({})", examples)
+    }
+}
@@ -0,0 +1,378 @@
+use crate::compression;
+use crate::couchdb::CouchPageInfo;
+use crate::couchdb::CouchRow;
+use crate::error::Error;
+use crate::source::SnippetSource;
+use std::fmt;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::thread;
+
+// Rows are parsed ahead by a reader thread, the channel keeps it from reading more than this many
+const READ_AHEAD: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DumpFormat {
+    // The response of _all_docs?include_docs=true
+    AllDocs,
+    // One document per line
+    Lines,
+}
+
+// Reads the documents from a file instead of CouchDB. The file is read front to back, a start key
+// skips the documents up to and including that id, so the order of the file doesn't matter for resuming.
+pub struct DumpFile {
+    path: String,
+    format: DumpFormat,
+    reader: Mutex<Option<DumpReader>>,
+}
+
+struct DumpReader {
+    receiver: mpsc::Receiver<Result<DumpItem, Error>>,
+    total_rows: u64,
+    // Id of the last row handed on, the next page continues right after it without reopening the file
+    last_key: Option<String>,
+}
+
+enum DumpItem {
+    TotalRows(u64),
+    Row(CouchRow),
+}
+
+impl DumpFile {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        // Fails early when the file doesn't exist, it is only read once the first page is requested
+        std::fs::metadata(path)
+            .map_err(|error| Error::Io{ path: path.to_string(), error })?;
+
+        let name = path.trim_end_matches(".gz");
+
+        let format = if name.ends_with(".jsonl") || name.ends_with(".ndjson") {
+            DumpFormat::Lines
+        } else {
+            DumpFormat::AllDocs
+        };
+
+        Ok(DumpFile{
+            path: path.to_string(),
+            format,
+            reader: Mutex::new(None),
+        })
+    }
+
+    fn start_reader(&self) -> DumpReader {
+        let (sender, receiver) = mpsc::sync_channel(READ_AHEAD);
+        let path = self.path.clone();
+        let format = self.format;
+
+        thread::spawn(move || {
+            let result = match format {
+                DumpFormat::AllDocs => read_all_docs(&path, &sender),
+                DumpFormat::Lines => read_lines(&path, &sender),
+            };
+
+            // Fails when the reader was dropped, then no one is interested in the error
+            if let Err(error) = result {
+                let _ = sender.send(Err(error));
+            }
+        });
+
+        DumpReader{
+            receiver,
+            total_rows: 0,
+            last_key: None,
+        }
+    }
+}
+
+impl SnippetSource for DumpFile {
+    fn stream_documents(&self, start_key: Option<String>, end_key: Option<&str>, limit: u64, on_row: &mut dyn FnMut(CouchRow) -> Result<(), Error>) -> Result<CouchPageInfo, Error> {
+        let mut reader_slot = self.reader.lock().unwrap();
+
+        // Continuing the previous page is the common case, anything else reads the file from the start
+        let mut skip_until = match reader_slot.as_ref() {
+            Some(reader) if reader.last_key == start_key => None,
+
+            _ => {
+                *reader_slot = Some(self.start_reader());
+                start_key
+            }
+        };
+
+        let reader = reader_slot.as_mut().unwrap();
+        let mut last_key = None;
+        let mut remaining = limit;
+
+        while remaining > 0 {
+            let row = match reader.receiver.recv() {
+                Ok(Ok(DumpItem::TotalRows(total_rows))) => {
+                    reader.total_rows = total_rows;
+                    continue;
+                }
+
+                Ok(Ok(DumpItem::Row(row))) => row,
+
+                Ok(Err(error)) => {
+                    *reader_slot = None;
+                    return Err(error);
+                }
+
+                // The reader thread is done
+                Err(_) => break,
+            };
+
+            if let Some(start_key) = &skip_until {
+                if &row.id == start_key {
+                    skip_until = None;
+                }
+
+                continue;
+            }
+
+            if end_key.is_some_and(|end_key| row.id.as_str() > end_key) {
+                break;
+            }
+
+            let id = row.id.clone();
+
+            if !id.starts_with("_design/") {
+                if let Err(error) = on_row(row) {
+                    *reader_slot = None;
+                    return Err(error);
+                }
+            }
+
+            remaining -= 1;
+            last_key = Some(id);
+        }
+
+        // Reached the end without finding it, otherwise every document would be skipped without a word
+        if let Some(key) = skip_until {
+            *reader_slot = None;
+            return Err(Error::MissingStartKey{ path: self.path.clone(), key });
+        }
+
+        reader.last_key = last_key.clone();
+
+        Ok(CouchPageInfo{
+            total_rows: reader.total_rows,
+            last_key,
+        })
+    }
+
+    fn get_revision(&self, id: &str, rev: &str) -> Result<serde_json::Value, Error> {
+        Err(Error::MissingRevision{ id: id.to_string(), rev: rev.to_string() })
+    }
+}
+
+fn read_all_docs(path: &str, sender: &mpsc::SyncSender<Result<DumpItem, Error>>) -> Result<(), Error> {
+    let reader = BufReader::new(compression::open(path)?);
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+
+    let result = serde::Deserializer::deserialize_map(&mut deserializer, AllDocsVisitor{ sender })
+        .and_then(|_| deserializer.end());
+
+    // Also fails when the reader was dropped, the error is then never received
+    result.map_err(|error| Error::FileDecode{ path: path.to_string(), error })
+}
+
+// The total isn't in the file, so the lines are counted before the documents are read
+fn read_lines(path: &str, sender: &mpsc::SyncSender<Result<DumpItem, Error>>) -> Result<(), Error> {
+    let mut total_rows = 0;
+
+    for line in BufReader::new(compression::open(path)?).split(b'\n') {
+        let line = line.map_err(|error| Error::Io{ path: path.to_string(), error })?;
+
+        if !line.iter().all(u8::is_ascii_whitespace) {
+            total_rows += 1;
+        }
+    }
+
+    if sender.send(Ok(DumpItem::TotalRows(total_rows))).is_err() {
+        return Ok(());
+    }
+
+    let reader = BufReader::new(compression::open(path)?);
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line.map_err(|error| Error::Io{ path: path.to_string(), error })?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let doc: serde_json::Value = serde_json::from_str(&line)
+            .map_err(|error| Error::FileDecode{ path: path.to_string(), error })?;
+
+        let id = match doc.get("_id").and_then(|id| id.as_str()) {
+            Some(id) => id.to_string(),
+            None => {
+                let error = serde::de::Error::custom(format!("document without _id on line {}", index + 1));
+                return Err(Error::FileDecode{ path: path.to_string(), error });
+            }
+        };
+
+        if sender.send(Ok(DumpItem::Row(CouchRow{ id, value: None, doc, conflict_revisions: Vec::new() }))).is_err() {
+            return Ok(());
+        }
+    }
+
+    Ok(())
+}
+
+// Sends the rows of an _all_docs response one by one while it is parsed
+struct AllDocsVisitor<'a> {
+    sender: &'a mpsc::SyncSender<Result<DumpItem, Error>>,
+}
+
+impl<'de, 'a> serde::de::Visitor<'de> for AllDocsVisitor<'a> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an _all_docs response")
+    }
+
+    fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "total_rows" => {
+                    let total_rows = map.next_value()?;
+                    self.send(DumpItem::TotalRows(total_rows))?;
+                }
+
+                "rows" => map.next_value_seed(RowsVisitor{ sender: self.sender })?,
+
+                _ => {
+                    map.next_value::<serde::de::IgnoredAny>()?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> AllDocsVisitor<'a> {
+    fn send<E: serde::de::Error>(&self, item: DumpItem) -> Result<(), E> {
+        send(self.sender, item)
+    }
+}
+
+struct RowsVisitor<'a> {
+    sender: &'a mpsc::SyncSender<Result<DumpItem, Error>>,
+}
+
+impl<'de, 'a> serde::de::DeserializeSeed<'de> for RowsVisitor<'a> {
+    type Value = ();
+
+    fn deserialize<D: serde::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, 'a> serde::de::Visitor<'de> for RowsVisitor<'a> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a list of rows")
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(row) = seq.next_element::<CouchRow>()? {
+            send(self.sender, DumpItem::Row(row))?;
+        }
+
+        Ok(())
+    }
+}
+
+// Stops parsing once the reader is gone
+fn send<E: serde::de::Error>(sender: &mpsc::SyncSender<Result<DumpItem, Error>>, item: DumpItem) -> Result<(), E> {
+    sender.send(Ok(item))
+        .map_err(|_| E::custom("the dump is no longer read"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> DumpFile {
+        DumpFile::open(&format!("{}/tests/fixtures/dump/{}", env!("CARGO_MANIFEST_DIR"), name)).unwrap()
+    }
+
+    // Reads a page and returns the ids of the documents and the page info
+    fn read_page(dump: &DumpFile, start_key: Option<&str>, end_key: Option<&str>, limit: u64) -> Result<(Vec<String>, CouchPageInfo), Error> {
+        let mut ids = Vec::new();
+        let page = dump.stream_documents(start_key.map(str::to_string), end_key, limit, &mut |row| {
+            ids.push(row.id);
+            Ok(())
+        })?;
+
+        Ok((ids, page))
+    }
+
+    #[test]
+    fn reads_all_docs_response() {
+        for name in ["all_docs.json", "all_docs.json.gz"] {
+            let (ids, page) = read_page(&fixture(name), None, None, 100).unwrap();
+
+            assert_eq!(ids, ["snip1", "snip2", "snip3", "snip4"], "{}", name);
+            assert_eq!(page.total_rows, 5, "{}", name);
+            assert_eq!(page.last_key.as_deref(), Some("snip4"), "{}", name);
+        }
+    }
+
+    #[test]
+    fn reads_lines_in_file_order() {
+        for name in ["documents.jsonl", "documents.jsonl.gz"] {
+            let (ids, page) = read_page(&fixture(name), None, None, 100).unwrap();
+
+            assert_eq!(ids, ["snip3", "snip1", "snip4", "snip2"], "{}", name);
+            assert_eq!(page.total_rows, 5, "{}", name);
+        }
+    }
+
+    #[test]
+    fn continues_with_the_next_page() {
+        let dump = fixture("all_docs.json");
+
+        // The design document counts against the limit like in _all_docs
+        let (ids, page) = read_page(&dump, None, None, 2).unwrap();
+        assert_eq!(ids, ["snip1"]);
+
+        let (ids, page) = read_page(&dump, page.last_key.as_deref(), None, 2).unwrap();
+        assert_eq!(ids, ["snip2", "snip3"]);
+
+        let (ids, page) = read_page(&dump, page.last_key.as_deref(), None, 2).unwrap();
+        assert_eq!(ids, ["snip4"]);
+
+        let (ids, page) = read_page(&dump, page.last_key.as_deref(), None, 2).unwrap();
+        assert!(ids.is_empty());
+        assert_eq!(page.last_key, None);
+    }
+
+    #[test]
+    fn starts_after_the_start_key_in_any_order() {
+        let (ids, _) = read_page(&fixture("documents.jsonl"), Some("snip1"), None, 100).unwrap();
+        assert_eq!(ids, ["snip4", "snip2"]);
+
+        let (ids, _) = read_page(&fixture("all_docs.json.gz"), Some("snip2"), Some("snip3"), 100).unwrap();
+        assert_eq!(ids, ["snip3"]);
+    }
+
+    #[test]
+    fn fails_when_the_start_key_is_missing() {
+        let dump = fixture("documents.jsonl.gz");
+
+        match read_page(&dump, Some("snip9"), None, 100) {
+            Err(Error::MissingStartKey { key, .. }) => assert_eq!(key, "snip9"),
+            result => panic!("expected a missing start key, got {:?}", result.map(|(ids, _)| ids)),
+        }
+
+        // The file is read again for the next page
+        let (ids, _) = read_page(&dump, None, None, 100).unwrap();
+        assert_eq!(ids.len(), 4);
+    }
+}
@@ -0,0 +1,145 @@
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    Io { path: String, error: io::Error },
+    CouchDbRequest { url: String, error: String, retryable: bool },
+    CouchDbStatus { url: String, status: u16, body: String },
+    JsonDecode { url: String, error: serde_json::Error },
+    FileDecode { path: String, error: serde_json::Error },
+    MissingStartKey { path: String, key: String },
+    MissingRevision { id: String, rev: String },
+    InvalidDocument(serde_json::Error),
+    Timestamp { field: &'static str, value: String, error: chrono::ParseError },
+    PostgresConstraint(postgres::Error),
+    Postgres(postgres::Error),
+    Tls(native_tls::Error),
+    Document { id: String, error: Box<Error> },
+    VerificationFailed { differences: usize },
+    UnsortedDocuments { id: String },
+    PartitionsExist { count: usize },
+    Interrupted { last_id: Option<String> },
+    OutputInterrupted { output_file: Option<String> },
+}
+
+impl Error {
+    // Errors caused by the content of a single document, everything else aborts the run
+    pub fn is_document_error(&self) -> bool {
+        match self {
+            Error::InvalidDocument(_) => true,
+            Error::Timestamp { .. } => true,
+            Error::PostgresConstraint(_) => true,
+            Error::Document { error, .. } => error.is_document_error(),
+            _ => false,
+        }
+    }
+
+    // CouchDB errors that may succeed when the request is sent again
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::CouchDbRequest { retryable, .. } => *retryable,
+            Error::CouchDbStatus { status, .. } => *status >= 500 || *status == 408 || *status == 429,
+            // The connection was lost while the body was read, or the body was cut short
+            Error::JsonDecode { error, .. } => error.is_io() || error.is_eof(),
+            _ => false,
+        }
+    }
+
+    // PostgreSQL errors that go away when the transaction is run again, if needed on a new connection
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Postgres(error) => {
+                let code = error.code().map(|code| code.code()).unwrap_or_default();
+
+                error.is_closed()
+                    // Serialization failure, deadlock and the server shutting down or starting up
+                    || matches!(code, "40001" | "40P01" | "57P01" | "57P02" | "57P03")
+                    // Connection exceptions
+                    || code.starts_with("08")
+                    // The connection couldn't be established or broke in the middle of a query
+                    || std::error::Error::source(error).is_some_and(|source| source.is::<io::Error>())
+            }
+
+            Error::Document { error, .. } => error.is_transient(),
+            _ => false,
+        }
+    }
+
+    // Stable identifier stored alongside failed documents
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Io { .. } => "io",
+            Error::CouchDbRequest { .. } => "couchdb_request",
+            Error::CouchDbStatus { .. } => "couchdb_status",
+            Error::JsonDecode { .. } => "json_decode",
+            Error::FileDecode { .. } => "file_decode",
+            Error::MissingStartKey { .. } => "missing_start_key",
+            Error::MissingRevision { .. } => "missing_revision",
+            Error::InvalidDocument(_) => "invalid_document",
+            Error::Timestamp { .. } => "timestamp",
+            Error::PostgresConstraint(_) => "postgres_constraint",
+            Error::Postgres(_) => "postgres",
+            Error::Tls(_) => "tls",
+            Error::Document { error, .. } => error.kind(),
+            Error::VerificationFailed { .. } => "verification_failed",
+            Error::UnsortedDocuments { .. } => "unsorted_documents",
+            Error::PartitionsExist { .. } => "partitions_exist",
+            Error::Interrupted { .. } => "interrupted",
+            Error::OutputInterrupted { .. } => "interrupted",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { path, error } => write!(f, "{}: {}", path, error),
+            Error::CouchDbRequest { url, error, .. } => write!(f, "CouchDB request to {} failed: {}", url, error),
+            Error::CouchDbStatus { url, status, body } => write!(f, "CouchDB responded with {} for {}: {}", status, url, body.trim()),
+            Error::JsonDecode { url, error } => write!(f, "failed to decode CouchDB response from {}: {}", url, error),
+            Error::FileDecode { path, error } => write!(f, "failed to read {}: {}", path, error),
+            Error::MissingStartKey { path, key } => write!(f, "the document '{}' to start after is not in {}, the start key or the checkpoint of --resume must come from the same dump", key, path),
+            Error::MissingRevision { id, rev } => write!(f, "revision {} of '{}' is not available, conflicting revisions can only be stored when reading from CouchDB", rev, id),
+            Error::InvalidDocument(error) => write!(f, "document has an unexpected shape: {}", error),
+            Error::Timestamp { field, value, error } => write!(f, "invalid {} timestamp '{}': {}", field, value, error),
+            Error::PostgresConstraint(error) => write!(f, "constraint violation: {}", postgres_message(error)),
+            Error::Postgres(error) => write!(f, "postgres error: {}", postgres_message(error)),
+            Error::Tls(error) => write!(f, "tls error: {}", error),
+            Error::Document { id, error } => write!(f, "document '{}': {}", id, error),
+            Error::VerificationFailed { differences } => write!(f, "verification found {} differences", differences),
+            Error::UnsortedDocuments { id } => write!(f, "the documents must be sorted by id to be verified, '{}' is out of order", id),
+            Error::PartitionsExist { count } => write!(f, "{} partitions are already planned, use --replace to plan them again", count),
+            Error::Interrupted { last_id: Some(last_id) } => write!(f, "interrupted, the last committed document is '{}', continue with --resume", last_id),
+            Error::Interrupted { last_id: None } => write!(f, "interrupted before the first batch was committed"),
+            Error::OutputInterrupted { output_file: Some(path) } => write!(f, "interrupted, {} is incomplete, run again to write all documents", path),
+            Error::OutputInterrupted { output_file: None } => write!(f, "interrupted"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// The Display impl of postgres::Error only says "db error", the details are in the DbError or the source
+fn postgres_message(error: &postgres::Error) -> String {
+    match (error.as_db_error(), std::error::Error::source(error)) {
+        (Some(db_error), _) => db_error.to_string(),
+        (None, Some(source)) => format!("{}: {}", error, source),
+        (None, None) => error.to_string(),
+    }
+}
+
+impl From<postgres::Error> for Error {
+    fn from(error: postgres::Error) -> Self {
+        // Class 22 is data exceptions, class 23 integrity constraint violations
+        let is_constraint = error.code()
+            .map(|code| code.code().starts_with("22") || code.code().starts_with("23"))
+            .unwrap_or(false);
+
+        if is_constraint {
+            Error::PostgresConstraint(error)
+        } else {
+            Error::Postgres(error)
+        }
+    }
+}
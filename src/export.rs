@@ -0,0 +1,266 @@
+use crate::compression::FileWriter;
+use crate::couchdb::CouchRow;
+use crate::dead_letter::DeadLetter;
+use crate::driver::Driver;
+use crate::error::Error;
+use crate::migrate::ErrorPolicy;
+use crate::migrate::Profile;
+use crate::sink;
+use crate::sink::SnippetSink;
+use std::collections::HashMap;
+
+#[derive(serde::Serialize)]
+struct ExportedSnippet<'a> {
+    slug: &'a str,
+    language: &'a str,
+    title: &'a str,
+    public: bool,
+    // Username of the owner, None for anonymous snippets and owners without a profile
+    owner: Option<&'a str>,
+    created: String,
+    modified: String,
+    files: Vec<ExportedFile<'a>>,
+}
+
+#[derive(serde::Serialize)]
+struct ExportedFile<'a> {
+    name: &'a str,
+    // The content comes from a JSON string in CouchDB, so it is valid UTF-8
+    content: String,
+}
+
+// Writes one JSON line per snippet. With a shard size a new file is started after that many snippets,
+// the shard number is added to the file name, i.e. snippets.jsonl.gz becomes snippets-00000.jsonl.gz.
+pub struct JsonlSink<'a> {
+    path: String,
+    shard_size: Option<usize>,
+    profiles: &'a HashMap<String, Profile>,
+    error_policy: ErrorPolicy,
+    dead_letter: &'a DeadLetter,
+    writer: Option<FileWriter>,
+    shard: usize,
+    shard_snippets: usize,
+}
+
+impl<'a> JsonlSink<'a> {
+    pub fn new(path: &str, shard_size: Option<usize>, profiles: &'a HashMap<String, Profile>, error_policy: ErrorPolicy, dead_letter: &'a DeadLetter) -> Self {
+        JsonlSink{
+            path: path.to_string(),
+            shard_size,
+            profiles,
+            error_policy,
+            dead_letter,
+            writer: None,
+            shard: 0,
+            shard_snippets: 0,
+        }
+    }
+
+    fn write_row(&mut self, row: &CouchRow) -> Result<(), Error> {
+        let (doc, snippet, files) = match sink::convert_row(row, self.profiles, self.error_policy, self.dead_letter)? {
+            Some(converted) => converted,
+            None => return Ok(()),
+        };
+
+        let exported = ExportedSnippet{
+            slug: &snippet.slug,
+            language: &snippet.language,
+            title: &snippet.title,
+            public: snippet.public,
+            owner: self.profiles.get(&doc.owner).map(|profile| profile.username.as_str()),
+            created: snippet.created.to_rfc3339(),
+            modified: snippet.modified.to_rfc3339(),
+            files: files.iter()
+                .map(|file| ExportedFile{ name: &file.name, content: String::from_utf8_lossy(&file.content).into_owned() })
+                .collect(),
+        };
+
+        let mut line = serde_json::to_vec(&exported)
+            .map_err(|error| Error::Io{ path: self.shard_path(), error: error.into() })?;
+        line.push(b'\n');
+
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => self.writer.insert(FileWriter::create(&self.shard_path())?),
+        };
+
+        writer.write_all(&line)?;
+        self.shard_snippets += 1;
+
+        if self.shard_size == Some(self.shard_snippets) {
+            self.finish_shard()?;
+        }
+
+        Ok(())
+    }
+
+    // Adds the shard number before the extensions of the file name
+    fn shard_path(&self) -> String {
+        if self.shard_size.is_none() {
+            return self.path.clone();
+        }
+
+        let name_start = self.path.rfind('/').map(|index| index + 1).unwrap_or(0);
+
+        match self.path[name_start..].find('.') {
+            Some(index) => {
+                let (stem, extensions) = self.path.split_at(name_start + index);
+                format!("{}-{:05}{}", stem, self.shard, extensions)
+            }
+
+            None => format!("{}-{:05}", self.path, self.shard),
+        }
+    }
+
+    fn finish_shard(&mut self) -> Result<(), Error> {
+        if let Some(writer) = self.writer.take() {
+            writer.finish()?;
+            info!("Wrote {} snippets to {}", self.shard_snippets, self.shard_path());
+
+            self.shard += 1;
+            self.shard_snippets = 0;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> SnippetSink for JsonlSink<'a> {
+    fn write_batch(&mut self, driver: &mut Driver) -> Result<bool, Error> {
+        driver.stream_batch(|row| self.write_row(&row))
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        // Without any snippets an empty file is written, so the export always has a first file
+        if self.writer.is_none() && self.shard == 0 {
+            self.writer = Some(FileWriter::create(&self.shard_path())?);
+        }
+
+        self.finish_shard()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrate;
+    use crate::migrate::MigrateOptions;
+    use crate::source::tests::document;
+    use crate::source::MemorySource;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn options(error_policy: ErrorPolicy) -> MigrateOptions {
+        MigrateOptions{ batch_size: 2, error_policy, ..Default::default() }
+    }
+
+    fn profiles() -> HashMap<String, Profile> {
+        let profile = Profile{ user_id: 1, api_id: "owner-a".to_string(), username: "alice".to_string() };
+        vec![(profile.api_id.clone(), profile)].into_iter().collect()
+    }
+
+    // A directory of its own for every test, they run in parallel
+    fn output_dir(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("export-{}-{}", process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_str().unwrap().to_string()
+    }
+
+    fn read_lines(path: &str) -> Vec<serde_json::Value> {
+        fs::read_to_string(path).unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    fn export(source: &MemorySource, path: &str, shard_size: Option<usize>, error_policy: ErrorPolicy) -> Result<usize, Error> {
+        export_with_dead_letter(source, path, shard_size, error_policy, &DeadLetter::new(None, false).unwrap())
+    }
+
+    fn export_with_dead_letter(source: &MemorySource, path: &str, shard_size: Option<usize>, error_policy: ErrorPolicy, dead_letter: &DeadLetter) -> Result<usize, Error> {
+        let profiles = profiles();
+        let mut sink = JsonlSink::new(path, shard_size, &profiles, error_policy, dead_letter);
+
+        migrate::process_loop(source, &mut sink, &options(error_policy), None, 0, None)
+    }
+
+    #[test]
+    fn writes_a_line_per_snippet() {
+        let mut owned = document("s2");
+        owned.doc["owner"] = serde_json::json!("owner-a");
+
+        let source = MemorySource::new(vec![document("s1"), owned, document("s3")]);
+        let dir = output_dir("lines");
+        let path = format!("{}/snippets.jsonl", dir);
+
+        assert_eq!(export(&source, &path, None, ErrorPolicy::Abort).unwrap(), 3);
+
+        let lines = read_lines(&path);
+        assert_eq!(lines.iter().map(|line| line["slug"].as_str().unwrap()).collect::<Vec<_>>(), ["s1", "s2", "s3"]);
+        assert_eq!(lines[0]["owner"], serde_json::Value::Null);
+        assert_eq!(lines[1]["owner"], "alice");
+        assert_eq!(lines[1]["files"], serde_json::json!([{"name": "main.py", "content": "print('s2')"}]));
+        assert_eq!(lines[1]["created"], "2020-03-01T10:00:00+00:00");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn starts_a_new_shard_after_the_shard_size() {
+        let source = MemorySource::new((1..=5).map(|i| document(&format!("s{}", i))).collect());
+        let dir = output_dir("shards");
+
+        export(&source, &format!("{}/snippets.jsonl", dir), Some(2), ErrorPolicy::Abort).unwrap();
+
+        let counts = ["00000", "00001", "00002"].iter()
+            .map(|shard| read_lines(&format!("{}/snippets-{}.jsonl", dir, shard)).len())
+            .collect::<Vec<_>>();
+        assert_eq!(counts, [2, 2, 1]);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn writes_an_empty_file_without_snippets() {
+        let source = MemorySource::new(Vec::new());
+        let dir = output_dir("empty");
+
+        export(&source, &format!("{}/snippets.jsonl", dir), Some(2), ErrorPolicy::Abort).unwrap();
+
+        assert!(read_lines(&format!("{}/snippets-00000.jsonl", dir)).is_empty());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn skips_or_stops_at_invalid_documents() {
+        let mut invalid = document("s2");
+        invalid.doc["created"] = serde_json::json!("yesterday");
+
+        let source = MemorySource::new(vec![document("s1"), invalid, document("s3")]);
+        let dir = output_dir("invalid");
+        let path = format!("{}/snippets.jsonl", dir);
+
+        let dead_letter_path = format!("{}/failed.jsonl", dir);
+        let dead_letter = DeadLetter::new(Some(&dead_letter_path), false).unwrap();
+
+        export_with_dead_letter(&source, &path, None, ErrorPolicy::Skip, &dead_letter).unwrap();
+        assert_eq!(read_lines(&path).iter().map(|line| line["slug"].as_str().unwrap()).collect::<Vec<_>>(), ["s1", "s3"]);
+
+        let failures = read_lines(&dead_letter_path);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0]["id"], "s2");
+        assert_eq!(failures[0]["kind"], "timestamp");
+        assert_eq!(failures[0]["document"]["created"], "yesterday");
+
+        match export(&source, &path, None, ErrorPolicy::Abort) {
+            Err(Error::Document { id, .. }) => assert_eq!(id, "s2"),
+            result => panic!("expected a document error, got {:?}", result),
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
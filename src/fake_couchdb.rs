@@ -0,0 +1,138 @@
+use crate::couchdb::Auth;
+use crate::couchdb::CouchDb;
+use crate::couchdb::RetryOptions;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time;
+
+// Stand-in for CouchDB in tests, serves recorded responses on a local port.
+// A request gets the next response of the first route whose path matches and whose query parameters are all in the request,
+// requests without a route get a 404 like a missing document.
+pub struct FakeCouchDb {
+    base_url: String,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+pub struct Route {
+    pub path: &'static str,
+    pub query: Vec<(&'static str, &'static str)>,
+    // Served in turn, the last one for every request after that
+    pub responses: Vec<Response>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Response {
+    Ok(&'static str),
+    Status(u16, &'static str),
+    // Announces the length of the whole body but closes the connection after the given number of bytes
+    Truncated(&'static str, usize),
+    // Closes the connection without answering
+    Reset,
+}
+
+impl Route {
+    pub fn new(path: &'static str, query: &[(&'static str, &'static str)], body: &'static str) -> Self {
+        Route::responses(path, query, vec![Response::Ok(body)])
+    }
+
+    pub fn responses(path: &'static str, query: &[(&'static str, &'static str)], responses: Vec<Response>) -> Self {
+        Route{ path, query: query.to_vec(), responses }
+    }
+}
+
+impl FakeCouchDb {
+    pub fn start(mut routes: Vec<Route>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::clone(&requests);
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => respond(stream, &mut routes, &received),
+                    Err(_) => return,
+                }
+            }
+        });
+
+        FakeCouchDb{ base_url, requests }
+    }
+
+    pub fn couchdb(&self) -> CouchDb {
+        self.couchdb_with_retries(0)
+    }
+
+    pub fn couchdb_with_retries(&self, retries: u32) -> CouchDb {
+        CouchDb::new(&self.base_url, "snippets", Auth::None, Vec::new(), RetryOptions{
+            retries,
+            initial_delay: time::Duration::from_millis(10),
+            timeout: time::Duration::from_secs(5),
+        })
+    }
+
+    // Path and query of every request received so far
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn respond(mut stream: TcpStream, routes: &mut [Route], requests: &Mutex<Vec<String>>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request_line = String::new();
+    let _ = reader.read_line(&mut request_line);
+
+    // The headers are read and ignored, the client only sends GET requests
+    loop {
+        let mut line = String::new();
+
+        match reader.read_line(&mut line) {
+            Ok(n) if n > 2 => (),
+            _ => break,
+        }
+    }
+
+    let target = request_line.split_whitespace().nth(1).unwrap_or("");
+    requests.lock().unwrap().push(target.to_string());
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let params = query.split('&')
+        .filter_map(|param| param.split_once('='))
+        .collect::<Vec<_>>();
+
+    let route = routes.iter_mut().find(|route| {
+        route.path == path && route.query.iter().all(|param| params.contains(param))
+    });
+
+    let response = match route {
+        Some(route) if route.responses.len() > 1 => route.responses.remove(0),
+        Some(route) => route.responses[0],
+        None => Response::Status(404, r#"{"error":"not_found","reason":"missing"}"#),
+    };
+
+    let (status, body, length) = match response {
+        Response::Ok(body) => (200, body, body.len()),
+        Response::Status(status, body) => (status, body, body.len()),
+        Response::Truncated(body, length) => (200, &body[..length], body.len()),
+        Response::Reset => return,
+    };
+
+    let _ = write!(stream, "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, reason(status), length, body);
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Object Not Found",
+        408 => "Request Timeout",
+        429 => "Too Many Requests",
+        _ => "Internal Server Error",
+    }
+}
@@ -0,0 +1,95 @@
+pub fn normalize_language(input: &str) -> String {
+    parse_language(input).unwrap_or_else(|| {
+        info!("Invalid language '{}', changing to 'plaintext'", input.to_ascii_lowercase());
+        "plaintext".to_string()
+    })
+}
+
+pub fn parse_language(input: &str) -> Option<String> {
+    let language = input.to_ascii_lowercase();
+
+    match language.as_str() {
+        "assembly" => Some(language.to_string()),
+        "ats" => Some(language.to_string()),
+        "bash" => Some(language.to_string()),
+        "clojure" => Some(language.to_string()),
+        "cobol" => Some(language.to_string()),
+        "coffeescript" => Some(language.to_string()),
+        "cpp" => Some(language.to_string()),
+        "c" => Some(language.to_string()),
+        "crystal" => Some(language.to_string()),
+        "csharp" => Some(language.to_string()),
+        "d" => Some(language.to_string()),
+        "elixir" => Some(language.to_string()),
+        "elm" => Some(language.to_string()),
+        "erlang" => Some(language.to_string()),
+        "fsharp" => Some(language.to_string()),
+        "go" => Some(language.to_string()),
+        "groovy" => Some(language.to_string()),
+        "haskell" => Some(language.to_string()),
+        "idris" => Some(language.to_string()),
+        "javascript" => Some(language.to_string()),
+        "julia" => Some(language.to_string()),
+        "kotlin" => Some(language.to_string()),
+        "lua" => Some(language.to_string()),
+        "mercury" => Some(language.to_string()),
+        "nim" => Some(language.to_string()),
+        "ocaml" => Some(language.to_string()),
+        "java" => Some(language.to_string()),
+        "perl" => Some(language.to_string()),
+        "php" => Some(language.to_string()),
+        "python" => Some(language.to_string()),
+        "raku" => Some(language.to_string()),
+        "ruby" => Some(language.to_string()),
+        "rust" => Some(language.to_string()),
+        "scala" => Some(language.to_string()),
+        "swift" => Some(language.to_string()),
+        "typescript" => Some(language.to_string()),
+        "plaintext" => Some(language.to_string()),
+        "perl6" => Some("raku".to_string()),
+        _ => None,
+    }
+}
+
+
+#[allow(dead_code)]
+#[derive(serde::Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    Assembly,
+    Ats,
+    Bash,
+    C,
+    Clojure,
+    Cobol,
+    CoffeeScript,
+    Cpp,
+    Crystal,
+    Csharp,
+    D,
+    Elixir,
+    Elm,
+    Erlang,
+    Fsharp,
+    Go,
+    Groovy,
+    Haskell,
+    Idris,
+    Java,
+    JavaScript,
+    Julia,
+    Kotlin,
+    Lua,
+    Mercury,
+    Nim,
+    Ocaml,
+    Perl,
+    Php,
+    Python,
+    Raku,
+    Ruby,
+    Rust,
+    Scala,
+    Swift,
+    TypeScript,
+}
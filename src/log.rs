@@ -0,0 +1,34 @@
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+pub const QUIET: usize = 0;
+pub const NORMAL: usize = 1;
+pub const VERBOSE: usize = 2;
+
+static VERBOSITY: AtomicUsize = AtomicUsize::new(NORMAL);
+
+pub fn set_verbosity(level: usize) {
+    VERBOSITY.store(level, Ordering::Relaxed);
+}
+
+pub fn enabled(level: usize) -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= level
+}
+
+// Progress output, hidden with --quiet
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::NORMAL) {
+            println!($($arg)*);
+        }
+    };
+}
+
+// Extra details, shown with --verbose
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::VERBOSE) {
+            println!($($arg)*);
+        }
+    };
+}
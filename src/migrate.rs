@@ -0,0 +1,422 @@
+use crate::checkpoint;
+use crate::checkpoint::Checkpoint;
+use crate::batch_insert;
+use crate::conflict;
+use crate::copy;
+use crate::couchdb::CouchDocument;
+use crate::couchdb::CouchRow;
+use crate::dead_letter::DeadLetter;
+use crate::dead_letter::DeadLetterBatch;
+use crate::driver::Driver;
+use crate::dead_letter::FailedDocument;
+use crate::error::Error;
+use crate::language::normalize_language;
+use crate::sink::SnippetSink;
+use crate::source::SnippetSource;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+#[derive(Debug)]
+pub struct Profile {
+    pub user_id: i64,
+    pub api_id: String,
+    pub username: String,
+}
+
+#[derive(Debug)]
+pub struct CodeSnippet {
+    pub slug: String,
+    pub language: String,
+    pub title: String,
+    pub public: bool,
+    pub user_id: Option<i64>,
+    pub created: chrono::DateTime<chrono::FixedOffset>,
+    pub modified: chrono::DateTime<chrono::FixedOffset>,
+}
+
+#[derive(Debug)]
+pub struct CodeFile {
+    pub name: String,
+    pub content: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ErrorPolicy {
+    Abort,
+    Skip,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum WriteMode {
+    /// One INSERT per snippet and file
+    Row,
+    /// Multi-row INSERTs of --insert-batch-size snippets, falls back to row for batches that fail
+    Batch,
+    /// COPY FROM STDIN (FORMAT binary) per batch, falls back to row for batches that fail
+    Copy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ConflictPolicy {
+    /// Migrate the revision CouchDB picked as the winner
+    Winner,
+    /// Leave documents with conflicts out
+    Skip,
+    /// Migrate the winner and store the conflicting revisions in the migration_conflict table
+    StoreRevisions,
+}
+
+pub struct MigrateOptions {
+    pub batch_size: u64,
+    pub limit: Option<usize>,
+    // Last document id to migrate (inclusive), used by partitions
+    pub end_key: Option<String>,
+    pub checkpoint_name: String,
+    pub upsert: bool,
+    pub error_policy: ErrorPolicy,
+    pub conflict_policy: ConflictPolicy,
+    pub write_mode: WriteMode,
+    pub insert_batch_size: usize,
+}
+
+// The defaults of the command line
+impl Default for MigrateOptions {
+    fn default() -> Self {
+        MigrateOptions{
+            batch_size: 1000,
+            limit: None,
+            end_key: None,
+            checkpoint_name: checkpoint::CHECKPOINT_NAME.to_string(),
+            upsert: false,
+            error_policy: ErrorPolicy::Abort,
+            conflict_policy: ConflictPolicy::Winner,
+            write_mode: WriteMode::Row,
+            insert_batch_size: 100,
+        }
+    }
+}
+
+// Rows written by the batch and copy modes
+pub struct BulkResult {
+    pub inserted: usize,
+    pub updated: usize,
+}
+
+pub fn load_profiles(client: &mut postgres::Client) -> Result<HashMap<String, Profile>, Error> {
+    let profiles = client.query("SELECT user_id, snippets_api_id, username FROM profile", &[])?
+        .iter()
+        .map(|row| {
+            let profile = Profile{
+                user_id: row.get(0),
+                api_id: row.get(1),
+                username: row.get(2),
+            };
+
+            (profile.api_id.clone(), profile)
+        })
+        .collect::<HashMap<String, Profile>>();
+
+    Ok(profiles)
+}
+
+pub fn process_loop(source: &dyn SnippetSource, sink: &mut dyn SnippetSink, options: &MigrateOptions, start_key: Option<String>, rows_processed: usize, limit: Option<usize>) -> Result<usize, Error> {
+    let mut driver = Driver::new(source, options.batch_size, options.end_key.clone(), start_key, rows_processed, limit)
+        .conflict_policy(options.conflict_policy)
+        .on_progress(|progress| {
+            info!("Processed {} of {}", progress.rows_processed, progress.total_rows);
+            debug!("{} batches in {:.1}s, {:.0} documents/s", progress.batches, progress.elapsed.as_secs_f64(), progress.rate());
+        });
+
+    while sink.write_batch(&mut driver)? {
+        driver.report_progress();
+    }
+
+    sink.finish()?;
+
+    Ok(driver.rows_processed())
+}
+
+// The row mode inserts every document while the page is still being parsed, the bulk modes need the whole page.
+// Returns false when there was nothing left to process.
+pub fn process_batch(client: &mut postgres::Client, driver: &mut Driver, profiles: &HashMap<String, Profile>, options: &MigrateOptions, dead_letter: &DeadLetter, last_id: &mut Option<String>) -> Result<bool, Error> {
+    let statements = prepare_statements(client, options.upsert)?;
+    let mut transaction = client.transaction()?;
+    let mut failures = dead_letter.batch();
+    let mut counts = InsertCounts::default();
+    let mut rows = Vec::new();
+
+    let more = driver.stream_batch(|row| {
+        *last_id = Some(row.id.clone());
+
+        if options.write_mode == WriteMode::Row {
+            conflict::save_revisions(&mut transaction, &row)?;
+            counts.add(insert_document_with_policy(&mut transaction, &statements, profiles, &row.id, &row.doc, options.error_policy, &mut failures)?);
+        } else {
+            rows.push(row);
+        }
+
+        Ok(())
+    })?;
+
+    if !more {
+        return Ok(false);
+    }
+
+    if options.write_mode == WriteMode::Row {
+        counts.log(options.upsert);
+    } else {
+        insert_rows(&mut transaction, &statements, profiles, options, &mut failures, &rows)?;
+    }
+
+    if let Some(last_id) = last_id {
+        // Saved in the same transaction so the checkpoint never points past committed rows
+        checkpoint::save(&mut transaction, &options.checkpoint_name, &Checkpoint{
+            last_id: last_id.clone(),
+            rows_processed: driver.rows_processed() as i64,
+        })?;
+    }
+
+    transaction.commit()?;
+    failures.commit()?;
+
+    Ok(true)
+}
+
+// A batch is committed when the checkpoint points at its last document, they are saved in the same transaction
+pub fn is_committed(client: &mut postgres::Client, options: &MigrateOptions, batch_last_id: Option<&str>) -> Result<bool, Error> {
+    let last_id = checkpoint::load(client, &options.checkpoint_name)?
+        .map(|checkpoint| checkpoint.last_id);
+
+    Ok(last_id.is_some() && last_id.as_deref() == batch_last_id)
+}
+
+pub fn insert_rows(transaction: &mut postgres::Transaction, statements: &Statements, profiles: &HashMap<String, Profile>, options: &MigrateOptions, failures: &mut DeadLetterBatch, rows: &[CouchRow]) -> Result<(), Error> {
+    // Outside of the savepoint, so they are kept when the bulk insert falls back to row by row
+    for row in rows {
+        conflict::save_revisions(transaction, row)?;
+    }
+
+    if options.write_mode != WriteMode::Row {
+        let mut savepoint = transaction.savepoint("bulk")?;
+
+        let result = match options.write_mode {
+            WriteMode::Batch => batch_insert::insert_documents(&mut savepoint, profiles, rows, options.insert_batch_size, options.upsert),
+            _ => copy::copy_documents(&mut savepoint, profiles, rows),
+        };
+
+        match result {
+            Ok(result) => {
+                savepoint.commit()?;
+
+                if options.upsert {
+                    info!("Inserted {}, updated {}, unchanged {}", result.inserted, result.updated, rows.len() - result.inserted - result.updated);
+                }
+
+                return Ok(());
+            }
+
+            // The row path applies the error policy to the failing documents
+            Err(error) if error.is_document_error() => {
+                savepoint.rollback()?;
+                info!("Writing the batch failed, inserting it row by row: {}", error);
+            }
+
+            Err(error) => return Err(error),
+        }
+    }
+
+    let mut counts = InsertCounts::default();
+
+    for row in rows {
+        counts.add(insert_document_with_policy(transaction, statements, profiles, &row.id, &row.doc, options.error_policy, failures)?);
+    }
+
+    counts.log(options.upsert);
+
+    Ok(())
+}
+
+// Outcome of inserting a batch row by row, None results are skipped documents
+#[derive(Default)]
+struct InsertCounts {
+    inserted: usize,
+    updated: usize,
+    unchanged: usize,
+    skipped: usize,
+}
+
+impl InsertCounts {
+    fn add(&mut self, result: Option<InsertResult>) {
+        match result {
+            Some(InsertResult::Inserted) => self.inserted += 1,
+            Some(InsertResult::Updated) => self.updated += 1,
+            Some(InsertResult::Unchanged) => self.unchanged += 1,
+            None => self.skipped += 1,
+        }
+    }
+
+    fn log(&self, upsert: bool) {
+        if upsert {
+            info!("Inserted {}, updated {}, unchanged {}", self.inserted, self.updated, self.unchanged);
+        }
+
+        if self.skipped > 0 {
+            info!("Skipped {} documents", self.skipped);
+        }
+    }
+}
+
+
+pub struct Statements {
+    insert_snippet: postgres::Statement,
+    insert_file: postgres::Statement,
+    delete_files: postgres::Statement,
+}
+
+pub enum InsertResult {
+    Inserted,
+    Updated,
+    Unchanged,
+}
+
+pub fn prepare_statements(client: &mut postgres::Client, upsert: bool) -> Result<Statements, Error> {
+    let insert_snippet = if upsert {
+        // Only touches existing snippets when the incoming document is newer, xmax = 0 means the row was inserted
+        client.prepare("
+            INSERT INTO code_snippet (slug, language, title, public, user_id, created, modified) VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (slug) DO UPDATE SET language = EXCLUDED.language, title = EXCLUDED.title, public = EXCLUDED.public, user_id = EXCLUDED.user_id, created = EXCLUDED.created, modified = EXCLUDED.modified
+            WHERE code_snippet.modified < EXCLUDED.modified
+            RETURNING id, xmax = 0
+        ")?
+    } else {
+        client.prepare("INSERT INTO code_snippet (slug, language, title, public, user_id, created, modified) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id, true")?
+    };
+
+    Ok(Statements{
+        insert_snippet,
+        insert_file: client.prepare("INSERT INTO code_file (code_snippet_id, name, content) VALUES ($1, $2, $3) RETURNING id")?,
+        delete_files: client.prepare("DELETE FROM code_file WHERE code_snippet_id = $1")?,
+    })
+}
+
+pub fn convert_document(doc: &CouchDocument, profiles: &HashMap<String, Profile>) -> Result<(CodeSnippet, Vec<CodeFile>), Error> {
+    let profile = profiles.get(&doc.owner);
+
+    let snippet = CodeSnippet{
+        slug: doc._id.clone(),
+        language: normalize_language(&doc.language),
+        title: doc.title.replace("\0", ""),
+        public: doc.public,
+        user_id: profile.map(|profile| profile.user_id),
+        created: parse_timestamp("created", &doc.created)?,
+        modified: parse_timestamp("modified", &doc.modified)?,
+    };
+
+    let files = doc.files.iter()
+        .map(|file| {
+            CodeFile{
+                name: file.name.replace("\0", ""),
+                content: file.content.clone(),
+            }
+        })
+        .collect();
+
+    Ok((snippet, files))
+}
+
+fn parse_timestamp(field: &'static str, value: &str) -> Result<chrono::DateTime<chrono::FixedOffset>, Error> {
+    chrono::DateTime::parse_from_rfc3339(value)
+        .map_err(|error| Error::Timestamp{ field, value: value.to_string(), error })
+}
+
+pub fn parse_document(raw: &serde_json::Value) -> Result<CouchDocument, Error> {
+    CouchDocument::deserialize(raw)
+        .map_err(Error::InvalidDocument)
+}
+
+// With the skip policy every document gets its own savepoint so a failing insert doesn't abort the batch
+#[allow(clippy::too_many_arguments)]
+pub fn insert_document_with_policy(transaction: &mut postgres::Transaction, statements: &Statements, profiles: &HashMap<String, Profile>, id: &str, raw: &serde_json::Value, error_policy: ErrorPolicy, failures: &mut DeadLetterBatch) -> Result<Option<InsertResult>, Error> {
+    match error_policy {
+        ErrorPolicy::Abort => {
+            parse_document(raw)
+                .and_then(|doc| insert_document(transaction, statements, profiles, &doc))
+                .map(Some)
+                .map_err(|error| Error::Document{ id: id.to_string(), error: Box::new(error) })
+        }
+
+        ErrorPolicy::Skip => {
+            let mut savepoint = transaction.savepoint("document")?;
+            let result = parse_document(raw)
+                .and_then(|doc| insert_document(&mut savepoint, statements, profiles, &doc));
+
+            match result {
+                Ok(result) => {
+                    savepoint.commit()?;
+                    Ok(Some(result))
+                }
+
+                Err(error) => {
+                    savepoint.rollback()?;
+                    handle_document_error(transaction, id, raw, error, error_policy, failures)?;
+                    Ok(None)
+                }
+            }
+        }
+    }
+}
+
+fn handle_document_error(transaction: &mut postgres::Transaction, id: &str, raw: &serde_json::Value, error: Error, error_policy: ErrorPolicy, failures: &mut DeadLetterBatch) -> Result<(), Error> {
+    if error_policy == ErrorPolicy::Skip && error.is_document_error() {
+        eprintln!("Skipping document '{}': {}", id, error);
+        failures.record(transaction, FailedDocument::new(id, raw, &error))
+    } else {
+        Err(Error::Document{ id: id.to_string(), error: Box::new(error) })
+    }
+}
+
+pub fn insert_document(transaction: &mut postgres::Transaction, statements: &Statements, profiles: &HashMap<String, Profile>, doc: &CouchDocument) -> Result<InsertResult, Error> {
+    let (snippet, files) = convert_document(doc, profiles)?;
+
+    let inserted_rows = transaction.query(&statements.insert_snippet, &[
+        &snippet.slug,
+        &snippet.language,
+        &snippet.title,
+        &snippet.public,
+        &snippet.user_id,
+        &snippet.created,
+        &snippet.modified,
+    ])?;
+
+    let (snippet_id, result): (i64, InsertResult) = match inserted_rows.last() {
+        Some(inserted_row) => {
+            let inserted: bool = inserted_row.get(1);
+            let snippet_id = inserted_row.get(0);
+
+            if inserted {
+                (snippet_id, InsertResult::Inserted)
+            } else {
+                transaction.execute(&statements.delete_files, &[&snippet_id])?;
+                (snippet_id, InsertResult::Updated)
+            }
+        }
+
+        None => {
+            return Ok(InsertResult::Unchanged);
+        }
+    };
+
+    for file in &files {
+        transaction.query(
+            &statements.insert_file,
+            &[
+                &snippet_id,
+                &file.name,
+                &file.content,
+            ],
+        )?;
+    }
+
+    Ok(result)
+}
+
@@ -0,0 +1,154 @@
+use crate::couchdb::CouchDb;
+use crate::error::Error;
+
+// A range of document ids, start_key is exclusive and end_key inclusive which matches
+// how the migration continues after a checkpoint. None means the start or end of the database.
+#[derive(Debug)]
+pub struct Partition {
+    pub index: i32,
+    pub start_key: Option<String>,
+    pub end_key: Option<String>,
+}
+
+impl Partition {
+    pub fn checkpoint_name(&self) -> String {
+        format!("partition-{}", self.index)
+    }
+}
+
+pub fn create_table(client: &mut postgres::Client) -> Result<(), Error> {
+    client.batch_execute("
+        CREATE TABLE IF NOT EXISTS migration_partition (
+            index integer PRIMARY KEY,
+            start_key text,
+            end_key text,
+            status text NOT NULL DEFAULT 'pending',
+            claimed_by text,
+            claimed_at timestamptz,
+            finished_at timestamptz,
+            error text
+        )
+    ")?;
+
+    Ok(())
+}
+
+// Splits _all_docs into ranges with about the same number of documents by looking up the id at every boundary
+pub fn plan(client: &mut postgres::Client, couchdb: &CouchDb, partitions: u32, replace: bool) -> Result<Vec<Partition>, Error> {
+    let existing: i64 = client.query_one("SELECT count(*) FROM migration_partition", &[])?.get(0);
+
+    if existing > 0 && !replace {
+        return Err(Error::PartitionsExist{ count: existing as usize });
+    }
+
+    let doc_count = couchdb.get_info()?.doc_count;
+    let mut boundaries = Vec::new();
+
+    for index in 1..partitions {
+        let position = doc_count * index as u64 / partitions as u64;
+
+        if let Some(key) = couchdb.get_key_at(position)? {
+            if boundaries.last() != Some(&key) {
+                boundaries.push(key);
+            }
+        }
+    }
+
+    let mut start_keys = vec![None];
+    start_keys.extend(boundaries.iter().cloned().map(Some));
+
+    let mut end_keys = boundaries.into_iter().map(Some).collect::<Vec<_>>();
+    end_keys.push(None);
+
+    let planned = start_keys.into_iter()
+        .zip(end_keys)
+        .enumerate()
+        .map(|(index, (start_key, end_key))| Partition{ index: index as i32, start_key, end_key })
+        .collect::<Vec<_>>();
+
+    let mut transaction = client.transaction()?;
+    transaction.execute("DELETE FROM migration_partition", &[])?;
+
+    for partition in &planned {
+        transaction.execute("INSERT INTO migration_partition (index, start_key, end_key) VALUES ($1, $2, $3)", &[
+            &partition.index,
+            &partition.start_key,
+            &partition.end_key,
+        ])?;
+    }
+
+    transaction.commit()?;
+
+    Ok(planned)
+}
+
+// Claims the given partition unless it's done, or otherwise the first pending one. SKIP LOCKED lets
+// several machines claim at the same time without getting the same partition.
+pub fn claim(client: &mut postgres::Client, index: Option<i32>, claimed_by: &str) -> Result<Option<Partition>, Error> {
+    let row = client.query_opt("
+        UPDATE migration_partition SET status = 'claimed', claimed_by = $2, claimed_at = now(), finished_at = NULL, error = NULL
+        WHERE index = (
+            SELECT index FROM migration_partition
+            WHERE ($1::integer IS NULL AND status = 'pending') OR (index = $1 AND status <> 'done')
+            ORDER BY index
+            LIMIT 1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING index, start_key, end_key
+    ", &[&index, &claimed_by])?;
+
+    Ok(row.map(|row| {
+        Partition{
+            index: row.get(0),
+            start_key: row.get(1),
+            end_key: row.get(2),
+        }
+    }))
+}
+
+pub fn finish(client: &mut postgres::Client, partition: &Partition) -> Result<(), Error> {
+    client.execute("UPDATE migration_partition SET status = 'done', finished_at = now() WHERE index = $1", &[&partition.index])?;
+    Ok(())
+}
+
+// Makes an interrupted partition available to be claimed again, it continues after its checkpoint
+pub fn release(client: &mut postgres::Client, partition: &Partition) -> Result<(), Error> {
+    client.execute("UPDATE migration_partition SET status = 'pending', claimed_by = NULL, claimed_at = NULL WHERE index = $1", &[&partition.index])?;
+    Ok(())
+}
+
+pub fn fail(client: &mut postgres::Client, partition: &Partition, error: &Error) -> Result<(), Error> {
+    client.execute("UPDATE migration_partition SET status = 'failed', finished_at = now(), error = $2 WHERE index = $1", &[&partition.index, &error.to_string()])?;
+    Ok(())
+}
+
+pub fn print_status(client: &mut postgres::Client) -> Result<(), Error> {
+    let rows = client.query("
+        SELECT p.index, p.start_key, p.end_key, p.status, p.claimed_by, p.error, c.rows_processed
+        FROM migration_partition p
+        LEFT JOIN migration_checkpoint c ON c.name = 'partition-' || p.index
+        ORDER BY p.index
+    ", &[])?;
+
+    for row in rows {
+        let index: i32 = row.get(0);
+        let start_key: Option<String> = row.get(1);
+        let end_key: Option<String> = row.get(2);
+        let status: String = row.get(3);
+        let claimed_by: Option<String> = row.get(4);
+        let error: Option<String> = row.get(5);
+        let rows_processed: Option<i64> = row.get(6);
+
+        println!("Partition {}: ({}, {}]  {}{}, {} rows processed{}",
+            index,
+            start_key.as_deref().unwrap_or("start"),
+            end_key.as_deref().unwrap_or("end"),
+            status,
+            claimed_by.map(|claimed_by| format!(" by {}", claimed_by)).unwrap_or_default(),
+            rows_processed.unwrap_or(0),
+            error.map(|error| format!(": {}", error)).unwrap_or_default(),
+        );
+    }
+
+    Ok(())
+}
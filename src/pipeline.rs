@@ -0,0 +1,283 @@
+use crate::checkpoint;
+use crate::checkpoint::Checkpoint;
+use crate::couchdb::CouchRow;
+use crate::database;
+use crate::dead_letter::DeadLetter;
+use crate::driver::Driver;
+use crate::error::Error;
+use crate::migrate;
+use crate::migrate::MigrateOptions;
+use crate::migrate::Profile;
+use crate::shutdown;
+use crate::source::SnippetSource;
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::thread;
+
+pub struct PipelineOptions {
+    pub workers: usize,
+    pub prefetch: usize,
+}
+
+struct Batch {
+    sequence: u64,
+    rows: Vec<CouchRow>,
+    rows_processed: usize,
+    total_rows: u64,
+}
+
+// Batches are inserted concurrently but committed in the order they were fetched,
+// so the checkpoint always points at the end of a contiguous range of committed documents
+struct CommitOrder {
+    state: Mutex<CommitState>,
+    turn_changed: Condvar,
+}
+
+struct CommitState {
+    next_sequence: u64,
+    // Batches fetched before the failing one are still committed, the later ones are rolled back
+    failed_sequence: Option<u64>,
+}
+
+impl CommitState {
+    fn is_cancelled(&self, sequence: u64) -> bool {
+        self.failed_sequence.is_some_and(|failed_sequence| sequence > failed_sequence)
+    }
+}
+
+impl CommitOrder {
+    fn new() -> Self {
+        CommitOrder{
+            state: Mutex::new(CommitState{ next_sequence: 0, failed_sequence: None }),
+            turn_changed: Condvar::new(),
+        }
+    }
+
+    // Returns false if a batch fetched before this one failed while waiting, the batch must then be rolled back
+    fn wait_for_turn(&self, sequence: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        while state.next_sequence != sequence && !state.is_cancelled(sequence) {
+            state = self.turn_changed.wait(state).unwrap();
+        }
+
+        !state.is_cancelled(sequence)
+    }
+
+    fn finish_turn(&self) {
+        self.state.lock().unwrap().next_sequence += 1;
+        self.turn_changed.notify_all();
+    }
+
+    // A worker failing before it received a batch passes u64::MAX, all batches already fetched can still be committed
+    fn fail(&self, sequence: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.failed_sequence = Some(state.failed_sequence.map_or(sequence, |failed_sequence| failed_sequence.min(sequence)));
+        self.turn_changed.notify_all();
+    }
+
+    fn is_cancelled(&self, sequence: u64) -> bool {
+        self.state.lock().unwrap().is_cancelled(sequence)
+    }
+
+    fn has_failed(&self) -> bool {
+        self.state.lock().unwrap().failed_sequence.is_some()
+    }
+}
+
+// A fetcher thread prefetches pages from CouchDB into a bounded channel, the writer workers
+// each insert whole pages with their own connection. Returns the number of documents processed.
+#[allow(clippy::too_many_arguments)]
+pub fn run<C>(source: &dyn SnippetSource, connect: C, profiles: &HashMap<String, Profile>, options: &MigrateOptions, pipeline_options: &PipelineOptions, dead_letter: &DeadLetter, start_key: Option<String>, rows_processed: usize) -> Result<usize, Error>
+    where C: Fn() -> Result<postgres::Client, Error> + Sync
+{
+    let (sender, receiver) = mpsc::sync_channel::<Batch>(pipeline_options.prefetch);
+    // Dropped together with the last worker so the fetcher stops when no one is left to receive
+    let receiver = Arc::new(Mutex::new(receiver));
+    let commit_order = CommitOrder::new();
+
+    thread::scope(|scope| {
+        let workers = (0..pipeline_options.workers)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                let connect = &connect;
+                let commit_order = &commit_order;
+
+                scope.spawn(move || write_batches(connect, profiles, options, dead_letter, &receiver, commit_order))
+            })
+            .collect::<Vec<_>>();
+
+        drop(receiver);
+
+        let fetch_result = fetch_batches(source, options, &commit_order, sender, start_key, rows_processed);
+
+        let mut final_rows_processed = rows_processed;
+        let mut first_error = None;
+
+        for worker in workers {
+            match worker.join().unwrap() {
+                Ok(worker_rows_processed) => final_rows_processed = final_rows_processed.max(worker_rows_processed),
+                Err(error) => first_error = first_error.or(Some(error)),
+            }
+        }
+
+        // A failing worker makes the fetcher stop, its error is the interesting one
+        match (first_error, fetch_result) {
+            (Some(error), _) => Err(error),
+            (None, Err(error)) => Err(error),
+            (None, Ok(())) => Ok(final_rows_processed),
+        }
+    })
+}
+
+// Unlike the sequential row mode the pipeline collects whole pages: a worker may have to insert its batch again
+// after a lost connection and batches are handed to whichever worker is free, so up to prefetch + workers pages are in memory
+fn fetch_batches(source: &dyn SnippetSource, options: &MigrateOptions, commit_order: &CommitOrder, sender: mpsc::SyncSender<Batch>, start_key: Option<String>, rows_processed: usize) -> Result<(), Error> {
+    let mut driver = Driver::new(source, options.batch_size, options.end_key.clone(), start_key, rows_processed, options.limit)
+        .conflict_policy(options.conflict_policy);
+    let mut sequence = 0;
+
+    while !commit_order.has_failed() {
+        let rows = match driver.next_batch()? {
+            Some(rows) => rows,
+            None => return Ok(()),
+        };
+
+        let batch = Batch{
+            sequence,
+            rows,
+            rows_processed: driver.rows_processed(),
+            total_rows: driver.progress().total_rows,
+        };
+
+        // Fails when all workers are gone, which only happens after an error
+        if sender.send(batch).is_err() {
+            return Ok(());
+        }
+
+        sequence += 1;
+    }
+
+    Ok(())
+}
+
+fn write_batches<C>(connect: &C, profiles: &HashMap<String, Profile>, options: &MigrateOptions, dead_letter: &DeadLetter, receiver: &Mutex<mpsc::Receiver<Batch>>, commit_order: &CommitOrder) -> Result<usize, Error>
+    where C: Fn() -> Result<postgres::Client, Error>
+{
+    let mut client = match connect() {
+        Ok(client) => client,
+        Err(error) => {
+            commit_order.fail(u64::MAX);
+            return Err(error);
+        }
+    };
+
+    let mut rows_processed = 0;
+
+    loop {
+        let batch = {
+            let receiver = receiver.lock().unwrap();
+
+            let batch = match receiver.recv() {
+                Ok(batch) => batch,
+                Err(_) => return Ok(rows_processed),
+            };
+
+            // Checked while holding the lock, so once a batch is dropped all later ones are dropped too
+            // and no worker waits for the turn of a dropped batch
+            if shutdown::requested() {
+                return Ok(rows_processed);
+            }
+
+            // Released before the batch is inserted so the other workers can receive
+            batch
+        };
+
+        // Fetched after a failing batch, it would only be rolled back
+        if commit_order.is_cancelled(batch.sequence) {
+            return Ok(rows_processed);
+        }
+
+        let last_id = batch.rows.last().map(|row| row.id.as_str());
+
+        // The turn isn't finished before the commit, so no other batch can have moved the checkpoint in the meantime
+        let already_committed = |client: &mut postgres::Client| Ok(migrate::is_committed(client, options, last_id)?.then_some(true));
+
+        let result = database::with_reconnect(&mut client, connect, already_committed, |client| {
+            let statements = migrate::prepare_statements(client, options.upsert)?;
+            let mut transaction = client.transaction()?;
+            let mut failures = dead_letter.batch();
+
+            migrate::insert_rows(&mut transaction, &statements, profiles, options, &mut failures, &batch.rows)?;
+
+            if !commit_order.wait_for_turn(batch.sequence) {
+                return Ok(false);
+            }
+
+            // The checkpoint row is only written in turn, otherwise workers would block each other on its lock
+            if let Some(row) = batch.rows.last() {
+                checkpoint::save(&mut transaction, &options.checkpoint_name, &Checkpoint{
+                    last_id: row.id.clone(),
+                    rows_processed: batch.rows_processed as i64,
+                })?;
+            }
+
+            transaction.commit()?;
+            failures.commit()?;
+
+            Ok(true)
+        });
+
+        let committed = match result {
+            Ok(committed) => committed,
+            Err(error) => {
+                commit_order.fail(batch.sequence);
+                return Err(error);
+            }
+        };
+
+        // A batch fetched before this one failed, this one was rolled back
+        if !committed {
+            return Ok(rows_processed);
+        }
+
+        rows_processed = batch.rows_processed;
+        info!("Processed {} of {}", rows_processed, batch.total_rows);
+
+        commit_order.finish_turn();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batches_before_the_failing_one_are_still_committed() {
+        let commit_order = CommitOrder::new();
+        commit_order.fail(2);
+
+        assert!(commit_order.wait_for_turn(0));
+        commit_order.finish_turn();
+        assert!(commit_order.wait_for_turn(1));
+        commit_order.finish_turn();
+
+        assert!(commit_order.is_cancelled(3));
+        assert!(!commit_order.wait_for_turn(3));
+    }
+
+    #[test]
+    fn the_earliest_failure_decides_what_is_rolled_back() {
+        let commit_order = CommitOrder::new();
+        commit_order.fail(u64::MAX);
+        commit_order.fail(4);
+
+        assert!(commit_order.has_failed());
+        assert!(commit_order.wait_for_turn(0));
+        assert!(!commit_order.is_cancelled(4));
+        assert!(commit_order.is_cancelled(5));
+    }
+}
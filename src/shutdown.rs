@@ -0,0 +1,25 @@
+use std::process;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// The first SIGINT/SIGTERM lets the running batches finish, the second one exits immediately
+pub fn install_handler() {
+    let result = ctrlc::set_handler(|| {
+        if REQUESTED.swap(true, Ordering::SeqCst) {
+            eprintln!("Exiting immediately, uncommitted batches are rolled back");
+            process::exit(130);
+        }
+
+        eprintln!("Stopping after the current batch, press Ctrl-C again to exit immediately");
+    });
+
+    if let Err(error) = result {
+        eprintln!("Failed to install the signal handler: {}", error);
+    }
+}
+
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
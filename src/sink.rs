@@ -0,0 +1,112 @@
+use crate::couchdb::CouchDocument;
+use crate::couchdb::CouchRow;
+use crate::database;
+use crate::dead_letter::DeadLetter;
+use crate::dead_letter::FailedDocument;
+use crate::driver::Driver;
+use crate::error::Error;
+use crate::migrate;
+use crate::migrate::CodeFile;
+use crate::migrate::CodeSnippet;
+use crate::migrate::ErrorPolicy;
+use crate::migrate::MigrateOptions;
+use crate::migrate::Profile;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Output {
+    /// Write the snippets and files to PostgreSQL
+    Postgres,
+    /// Write every snippet with its files as a JSON line to --output-file
+    Jsonl,
+    /// Write the statements to a SQL script at --output-file instead of executing them
+    Sql,
+    /// Read the documents without writing them anywhere, i.e. to measure how fast the source is
+    Null,
+}
+
+// Where the sequential loop writes the documents: PostgreSQL with --sequential and the outputs other than PostgreSQL.
+// The sink pulls the next batch from the driver itself, so it can write the documents while the page is read
+// and rewind the driver when a batch has to be written again. The default pipeline doesn't go through a sink,
+// its workers write to PostgreSQL with their own connections while the next pages are fetched.
+pub trait SnippetSink {
+    // Writes the next batch, returns false when there was nothing left to process
+    fn write_batch(&mut self, driver: &mut Driver) -> Result<bool, Error>;
+
+    // Called after the last batch, also when the run was interrupted
+    fn finish(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+pub struct PostgresSink<'a> {
+    pub client: &'a mut postgres::Client,
+    // Opens a new connection when the current one was lost
+    pub connect: &'a dyn Fn() -> Result<postgres::Client, Error>,
+    pub profiles: &'a HashMap<String, Profile>,
+    pub options: &'a MigrateOptions,
+    pub dead_letter: &'a DeadLetter,
+}
+
+impl<'a> SnippetSink for PostgresSink<'a> {
+    fn write_batch(&mut self, driver: &mut Driver) -> Result<bool, Error> {
+        let (profiles, options, dead_letter) = (self.profiles, self.options, self.dead_letter);
+        let position = driver.position();
+        // Shared with the check for a commit that went through, which only runs between attempts
+        let last_id = RefCell::new(None);
+
+        database::with_reconnect(self.client, self.connect,
+            |client| Ok(migrate::is_committed(client, options, last_id.borrow().as_deref())?.then_some(true)),
+            |client| {
+                driver.rewind(&position);
+                migrate::process_batch(client, driver, profiles, options, dead_letter, &mut last_id.borrow_mut())
+            })
+    }
+}
+
+pub struct NullSink;
+
+impl SnippetSink for NullSink {
+    fn write_batch(&mut self, driver: &mut Driver) -> Result<bool, Error> {
+        driver.stream_batch(|_| Ok(()))
+    }
+}
+
+// Converts a document for the sinks that write files. Failing documents are skipped with --on-error=skip
+// and stored in the --dead-letter-file, the table isn't available without PostgreSQL as the output.
+pub fn convert_row(row: &CouchRow, profiles: &HashMap<String, Profile>, error_policy: ErrorPolicy, dead_letter: &DeadLetter) -> Result<Option<(CouchDocument, CodeSnippet, Vec<CodeFile>)>, Error> {
+    let result = migrate::parse_document(&row.doc)
+        .and_then(|doc| {
+            let (snippet, files) = migrate::convert_document(&doc, profiles)?;
+            Ok((doc, snippet, files))
+        });
+
+    match result {
+        Ok(converted) => Ok(Some(converted)),
+
+        Err(error) if error_policy == ErrorPolicy::Skip && error.is_document_error() => {
+            eprintln!("Skipping document '{}': {}", row.id, error);
+            dead_letter.append(&FailedDocument::new(&row.id, &row.doc, &error))?;
+            Ok(None)
+        }
+
+        Err(error) => Err(Error::Document{ id: row.id.clone(), error: Box::new(error) }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::tests::document;
+    use crate::source::MemorySource;
+
+    #[test]
+    fn null_sink_reads_every_document() {
+        let source = MemorySource::new((1..=5).map(|i| document(&format!("s{}", i))).collect());
+        let options = MigrateOptions{ batch_size: 2, ..Default::default() };
+
+        assert_eq!(migrate::process_loop(&source, &mut NullSink, &options, None, 0, None).unwrap(), 5);
+        assert_eq!(migrate::process_loop(&source, &mut NullSink, &options, Some("s3".to_string()), 3, Some(1)).unwrap(), 4);
+    }
+}
@@ -0,0 +1,188 @@
+use crate::couchdb::CouchDb;
+use crate::couchdb::CouchPage;
+use crate::couchdb::CouchPageInfo;
+use crate::couchdb::CouchRow;
+use crate::error::Error;
+#[cfg(test)]
+use std::collections::HashMap;
+
+// Where the documents are read from, CouchDB itself, a dump file or documents held in memory
+pub trait SnippetSource: Sync {
+    // Hands at most limit documents after start_key, up to end_key (inclusive), to on_row.
+    // Design documents are left out, the returned last_key is None when nothing was left.
+    fn stream_documents(&self, start_key: Option<String>, end_key: Option<&str>, limit: u64, on_row: &mut dyn FnMut(CouchRow) -> Result<(), Error>) -> Result<CouchPageInfo, Error>;
+
+    // A revision that lost a conflict, see --conflicts=store-revisions
+    fn get_revision(&self, id: &str, rev: &str) -> Result<serde_json::Value, Error>;
+
+    fn get_documents(&self, start_key: Option<String>, end_key: Option<&str>, limit: u64) -> Result<CouchPage, Error> {
+        let mut rows = Vec::new();
+
+        let info = self.stream_documents(start_key, end_key, limit, &mut |row| {
+            rows.push(row);
+            Ok(())
+        })?;
+
+        Ok(CouchPage{
+            total_rows: info.total_rows,
+            rows,
+            last_key: info.last_key,
+        })
+    }
+}
+
+impl SnippetSource for CouchDb {
+    fn stream_documents(&self, start_key: Option<String>, end_key: Option<&str>, limit: u64, on_row: &mut dyn FnMut(CouchRow) -> Result<(), Error>) -> Result<CouchPageInfo, Error> {
+        CouchDb::stream_documents(self, start_key, end_key, limit, on_row)
+    }
+
+    fn get_revision(&self, id: &str, rev: &str) -> Result<serde_json::Value, Error> {
+        CouchDb::get_revision(self, id, rev)
+    }
+}
+
+// Documents held in memory, used by the tests to run the migration against a fixed set of documents without CouchDB
+#[cfg(test)]
+pub struct MemorySource {
+    // Sorted by id like _all_docs
+    rows: Vec<CouchRow>,
+    // Revisions that lost a conflict by (id, rev)
+    revisions: HashMap<(String, String), serde_json::Value>,
+}
+
+#[cfg(test)]
+impl MemorySource {
+    pub fn new(mut rows: Vec<CouchRow>) -> Self {
+        rows.sort_by(|a, b| a.id.cmp(&b.id));
+        MemorySource{ rows, revisions: HashMap::new() }
+    }
+
+    pub fn revision(mut self, id: &str, rev: &str, doc: serde_json::Value) -> Self {
+        self.revisions.insert((id.to_string(), rev.to_string()), doc);
+        self
+    }
+}
+
+#[cfg(test)]
+impl SnippetSource for MemorySource {
+    fn stream_documents(&self, start_key: Option<String>, end_key: Option<&str>, limit: u64, on_row: &mut dyn FnMut(CouchRow) -> Result<(), Error>) -> Result<CouchPageInfo, Error> {
+        let start = match &start_key {
+            Some(start_key) => self.rows.partition_point(|row| &row.id <= start_key),
+            None => 0,
+        };
+
+        let page = self.rows[start..].iter()
+            .take_while(|row| end_key.is_none_or(|end_key| row.id.as_str() <= end_key))
+            .take(limit as usize);
+
+        let mut last_key = None;
+
+        for row in page {
+            if !row.id.starts_with("_design/") {
+                on_row(row.clone())?;
+            }
+
+            last_key = Some(row.id.clone());
+        }
+
+        Ok(CouchPageInfo{
+            total_rows: self.rows.len() as u64,
+            last_key,
+        })
+    }
+
+    fn get_revision(&self, id: &str, rev: &str) -> Result<serde_json::Value, Error> {
+        self.revisions.get(&(id.to_string(), rev.to_string()))
+            .cloned()
+            .ok_or_else(|| Error::MissingRevision{ id: id.to_string(), rev: rev.to_string() })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // A row like _all_docs?include_docs=true returns it for a valid snippet
+    pub fn document(id: &str) -> CouchRow {
+        row(id, serde_json::json!({
+            "_id": id,
+            "_rev": "1-a",
+            "created": "2020-03-01T10:00:00Z",
+            "modified": "2020-03-02T10:00:00Z",
+            "language": "python",
+            "title": format!("Title of {}", id),
+            "public": true,
+            "owner": "anonymous",
+            "files": [{"name": "main.py", "content": format!("print('{}')", id)}],
+        }))
+    }
+
+    pub fn row(id: &str, doc: serde_json::Value) -> CouchRow {
+        CouchRow{ id: id.to_string(), value: None, doc, conflict_revisions: Vec::new() }
+    }
+
+    fn source() -> MemorySource {
+        MemorySource::new(vec![
+            document("c"),
+            document("a"),
+            row("_design/snippets", serde_json::json!({"_id": "_design/snippets"})),
+            document("d"),
+            document("b"),
+        ])
+    }
+
+    fn read_page(source: &MemorySource, start_key: Option<&str>, end_key: Option<&str>, limit: u64) -> (Vec<String>, CouchPageInfo) {
+        let mut ids = Vec::new();
+        let page = source.stream_documents(start_key.map(str::to_string), end_key, limit, &mut |row| {
+            ids.push(row.id);
+            Ok(())
+        }).unwrap();
+
+        (ids, page)
+    }
+
+    #[test]
+    fn leaves_out_design_documents() {
+        let (ids, page) = read_page(&source(), None, None, 100);
+
+        assert_eq!(ids, ["a", "b", "c", "d"]);
+        assert_eq!(page.total_rows, 5);
+        assert_eq!(page.last_key.as_deref(), Some("d"));
+
+        // The design document sorts first, a page with only it still moves the cursor
+        let (ids, page) = read_page(&source(), None, None, 1);
+        assert!(ids.is_empty());
+        assert_eq!(page.last_key.as_deref(), Some("_design/snippets"));
+    }
+
+    #[test]
+    fn starts_after_the_start_key() {
+        assert_eq!(read_page(&source(), Some("b"), None, 100).0, ["c", "d"]);
+
+        // A start key between two ids continues with the next one
+        assert_eq!(read_page(&source(), Some("bb"), None, 100).0, ["c", "d"]);
+
+        let (ids, page) = read_page(&source(), Some("d"), None, 100);
+        assert!(ids.is_empty());
+        assert_eq!(page.last_key, None);
+    }
+
+    #[test]
+    fn stops_at_the_end_key_and_limit() {
+        assert_eq!(read_page(&source(), None, Some("b"), 100).0, ["a", "b"]);
+        assert_eq!(read_page(&source(), None, Some("bb"), 100).0, ["a", "b"]);
+        assert_eq!(read_page(&source(), Some("a"), Some("c"), 1).0, ["b"]);
+
+        let (ids, page) = read_page(&source(), Some("b"), Some("b"), 100);
+        assert!(ids.is_empty());
+        assert_eq!(page.last_key, None);
+    }
+
+    #[test]
+    fn returns_stored_revisions() {
+        let source = source().revision("a", "2-b", serde_json::json!({"title": "lost"}));
+
+        assert_eq!(source.get_revision("a", "2-b").unwrap()["title"], "lost");
+        assert!(matches!(source.get_revision("a", "2-c"), Err(Error::MissingRevision { .. })));
+    }
+}
@@ -0,0 +1,330 @@
+use crate::compression::FileWriter;
+use crate::dead_letter::DeadLetter;
+use crate::driver::Driver;
+use crate::error::Error;
+use crate::migrate::CodeFile;
+use crate::migrate::CodeSnippet;
+use crate::migrate::ErrorPolicy;
+use crate::migrate::Profile;
+use crate::shutdown;
+use crate::sink;
+use crate::sink::SnippetSink;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum SqlFormat {
+    /// One INSERT statement per snippet and its files, works with --upsert
+    Insert,
+    /// COPY data sections like pg_dump writes them, the snippet ids are taken from the sequence of the connected database
+    Copy,
+}
+
+// Writes the statements the PostgreSQL sink would have executed to a script, which is wrapped in a transaction.
+// An error stops the script before the COMMIT and an interrupted run ends it with a ROLLBACK,
+// so applying an incomplete script doesn't change anything.
+pub struct SqlScriptSink<'a> {
+    writer: Option<FileWriter>,
+    format: SqlFormat,
+    upsert: bool,
+    profiles: &'a HashMap<String, Profile>,
+    error_policy: ErrorPolicy,
+    dead_letter: &'a DeadLetter,
+    // Used by the copy format to reserve the snippet ids
+    client: Option<postgres::Client>,
+}
+
+impl<'a> SqlScriptSink<'a> {
+    pub fn create(path: &str, format: SqlFormat, upsert: bool, profiles: &'a HashMap<String, Profile>, error_policy: ErrorPolicy, dead_letter: &'a DeadLetter, client: Option<postgres::Client>) -> Result<Self, Error> {
+        let mut writer = FileWriter::create(path)?;
+
+        writer.write_all(b"-- Generated by glot-snippets-migration-tool\n\n")?;
+        writer.write_all(b"SET client_encoding = 'UTF8';\n")?;
+        writer.write_all(b"SET standard_conforming_strings = on;\n\n")?;
+        writer.write_all(b"BEGIN;\n\n")?;
+
+        Ok(SqlScriptSink{
+            writer: Some(writer),
+            format,
+            upsert,
+            profiles,
+            error_policy,
+            dead_letter,
+            client,
+        })
+    }
+
+    fn write(&mut self, script: &str) -> Result<(), Error> {
+        match &mut self.writer {
+            Some(writer) => writer.write_all(script.as_bytes()),
+            None => Ok(()),
+        }
+    }
+
+    fn write_copy_sections(&mut self, documents: &[(CodeSnippet, Vec<CodeFile>)]) -> Result<(), Error> {
+        if documents.is_empty() {
+            return Ok(());
+        }
+
+        // Taken from the sequence like the copy write mode does, so the ids don't collide with snippets inserted in the meantime
+        let client = self.client.as_mut().expect("the copy format needs a connection");
+        let ids = client.query("SELECT nextval(pg_get_serial_sequence('code_snippet', 'id')) FROM generate_series(1, $1::bigint)", &[&(documents.len() as i64)])?
+            .iter()
+            .map(|row| row.get(0))
+            .collect::<Vec<i64>>();
+
+        self.write(&copy_sections(&ids, documents))
+    }
+}
+
+impl<'a> SnippetSink for SqlScriptSink<'a> {
+    fn write_batch(&mut self, driver: &mut Driver) -> Result<bool, Error> {
+        let (profiles, error_policy, dead_letter, format, upsert) = (self.profiles, self.error_policy, self.dead_letter, self.format, self.upsert);
+        let mut documents = Vec::new();
+
+        let more = driver.stream_batch(|row| {
+            let (_, snippet, files) = match sink::convert_row(&row, profiles, error_policy, dead_letter)? {
+                Some(converted) => converted,
+                None => return Ok(()),
+            };
+
+            match format {
+                SqlFormat::Insert => self.write(&insert_statement(&snippet, &files, upsert)),
+
+                // The COPY sections need all snippets of the batch before the files
+                SqlFormat::Copy => {
+                    documents.push((snippet, files));
+                    Ok(())
+                }
+            }
+        })?;
+
+        self.write_copy_sections(&documents)?;
+
+        Ok(more)
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        if let Some(mut writer) = self.writer.take() {
+            // An interrupted run stops after any batch, applying the script must not leave part of the snippets behind
+            if shutdown::requested() {
+                writer.write_all(b"-- The run was interrupted, this script is incomplete\nROLLBACK;\n")?;
+            } else {
+                writer.write_all(b"COMMIT;\n")?;
+            }
+
+            writer.finish()?;
+        }
+
+        Ok(())
+    }
+}
+
+fn copy_sections(ids: &[i64], documents: &[(CodeSnippet, Vec<CodeFile>)]) -> String {
+    let mut script = String::from("COPY code_snippet (id, slug, language, title, public, user_id, created, modified) FROM stdin;\n");
+
+    for (id, (snippet, _)) in ids.iter().zip(documents) {
+        let _ = writeln!(script, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            id,
+            copy_text(&snippet.slug),
+            copy_text(&snippet.language),
+            copy_text(&snippet.title),
+            if snippet.public { "t" } else { "f" },
+            snippet.user_id.map(|user_id| user_id.to_string()).unwrap_or_else(|| "\\N".to_string()),
+            snippet.created.to_rfc3339(),
+            snippet.modified.to_rfc3339(),
+        );
+    }
+
+    script.push_str("\\.\n\nCOPY code_file (code_snippet_id, name, content) FROM stdin;\n");
+
+    for (id, (_, files)) in ids.iter().zip(documents) {
+        for file in files {
+            // The backslash of the bytea hex format is escaped like any other backslash in COPY text
+            let _ = writeln!(script, "{}\t{}\t\\\\x{}", id, copy_text(&file.name), hex(&file.content));
+        }
+    }
+
+    script.push_str("\\.\n\n");
+
+    script
+}
+
+// The files are inserted in the same statement with the id returned for the snippet,
+// an upsert that leaves the snippet unchanged returns no id and inserts no files
+fn insert_statement(snippet: &CodeSnippet, files: &[CodeFile], upsert: bool) -> String {
+    let mut statement = format!("INSERT INTO code_snippet (slug, language, title, public, user_id, created, modified) VALUES ({}, {}, {}, {}, {}, {}, {})",
+        literal(&snippet.slug),
+        literal(&snippet.language),
+        literal(&snippet.title),
+        snippet.public,
+        snippet.user_id.map(|user_id| user_id.to_string()).unwrap_or_else(|| "NULL".to_string()),
+        literal(&snippet.created.to_rfc3339()),
+        literal(&snippet.modified.to_rfc3339()),
+    );
+
+    if upsert {
+        statement.push_str(" ON CONFLICT (slug) DO UPDATE SET language = EXCLUDED.language, title = EXCLUDED.title, public = EXCLUDED.public, user_id = EXCLUDED.user_id, created = EXCLUDED.created, modified = EXCLUDED.modified WHERE code_snippet.modified < EXCLUDED.modified");
+    }
+
+    if files.is_empty() && !upsert {
+        return format!("{};\n", statement);
+    }
+
+    // Files of an updated snippet are replaced, the DELETE doesn't see the files inserted by the same statement
+    if files.is_empty() {
+        return format!("WITH snippet AS ({} RETURNING id) DELETE FROM code_file WHERE code_snippet_id IN (SELECT id FROM snippet);\n", statement);
+    }
+
+    let delete_files = if upsert {
+        ", deleted AS (DELETE FROM code_file WHERE code_snippet_id IN (SELECT id FROM snippet))"
+    } else {
+        ""
+    };
+
+    let values = files.iter()
+        .map(|file| format!("({}, '\\x{}'::bytea)", literal(&file.name), hex(&file.content)))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!("WITH snippet AS ({} RETURNING id){} INSERT INTO code_file (code_snippet_id, name, content) SELECT snippet.id, file.name, file.content FROM snippet, (VALUES {}) AS file (name, content);\n",
+        statement,
+        delete_files,
+        values,
+    )
+}
+
+// standard_conforming_strings is set at the top of the script, so only quotes need to be escaped
+fn literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+fn copy_text(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+
+    for byte in bytes {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snippet(title: &str) -> CodeSnippet {
+        let timestamp = chrono::DateTime::parse_from_rfc3339("2020-03-01T10:00:00Z").unwrap();
+
+        CodeSnippet{
+            slug: "s1".to_string(),
+            language: "python".to_string(),
+            title: title.to_string(),
+            public: true,
+            user_id: None,
+            created: timestamp,
+            modified: timestamp,
+        }
+    }
+
+    fn file(name: &str, content: &[u8]) -> CodeFile {
+        CodeFile{ name: name.to_string(), content: content.to_vec() }
+    }
+
+    #[test]
+    fn escapes_literals() {
+        assert_eq!(literal("it's"), "'it''s'");
+        assert_eq!(literal("C:\\temp\\new"), "'C:\\temp\\new'");
+        assert_eq!(literal("tab\tline\r\nend"), "'tab\tline\r\nend'");
+        assert_eq!(literal("Grüße, 世界"), "'Grüße, 世界'");
+    }
+
+    #[test]
+    fn escapes_copy_text() {
+        assert_eq!(copy_text("it's"), "it's");
+        assert_eq!(copy_text("C:\\temp"), "C:\\\\temp");
+        assert_eq!(copy_text("tab\tline\r\nend"), "tab\\tline\\r\\nend");
+        assert_eq!(copy_text("Grüße, 世界"), "Grüße, 世界");
+    }
+
+    #[test]
+    fn writes_bytea_as_hex() {
+        assert_eq!(hex(b""), "");
+        assert_eq!(hex(&[0x00, 0x0f, 0xab, 0xff]), "000fabff");
+        assert_eq!(hex("é\n".as_bytes()), "c3a90a");
+    }
+
+    #[test]
+    fn writes_copy_sections() {
+        let mut owned = snippet("tab\there\\");
+        owned.user_id = Some(7);
+
+        let documents = vec![
+            (owned, vec![file("main.py", b"x\n")]),
+            (snippet("Grüße"), vec![file("a\tb", &[0, 255]), file("c", b"")]),
+        ];
+
+        assert_eq!(copy_sections(&[10, 11], &documents), concat!(
+            "COPY code_snippet (id, slug, language, title, public, user_id, created, modified) FROM stdin;\n",
+            "10\ts1\tpython\ttab\\there\\\\\tt\t7\t2020-03-01T10:00:00+00:00\t2020-03-01T10:00:00+00:00\n",
+            "11\ts1\tpython\tGrüße\tt\t\\N\t2020-03-01T10:00:00+00:00\t2020-03-01T10:00:00+00:00\n",
+            "\\.\n\n",
+            "COPY code_file (code_snippet_id, name, content) FROM stdin;\n",
+            "10\tmain.py\t\\\\x780a\n",
+            "11\ta\\tb\t\\\\x00ff\n",
+            "11\tc\t\\\\x\n",
+            "\\.\n\n",
+        ));
+    }
+
+    #[test]
+    fn inserts_a_snippet_without_files() {
+        assert_eq!(
+            insert_statement(&snippet("it's"), &[], false),
+            "INSERT INTO code_snippet (slug, language, title, public, user_id, created, modified) VALUES ('s1', 'python', 'it''s', true, NULL, '2020-03-01T10:00:00+00:00', '2020-03-01T10:00:00+00:00');\n",
+        );
+    }
+
+    #[test]
+    fn inserts_the_files_with_the_snippet_id() {
+        let statement = insert_statement(&snippet("title"), &[file("main.py", b"print('hi')\n"), file("a'b.txt", &[0, 255])], false);
+
+        assert!(statement.starts_with("WITH snippet AS (INSERT INTO code_snippet "));
+        assert!(statement.ends_with(" RETURNING id) INSERT INTO code_file (code_snippet_id, name, content) SELECT snippet.id, file.name, file.content FROM snippet, (VALUES ('main.py', '\\x7072696e742827686927290a'::bytea), ('a''b.txt', '\\x00ff'::bytea)) AS file (name, content);\n"));
+        assert!(!statement.contains("ON CONFLICT"));
+        assert!(!statement.contains("DELETE"));
+    }
+
+    #[test]
+    fn upsert_without_files_deletes_the_old_files() {
+        let statement = insert_statement(&snippet("title"), &[], true);
+
+        assert!(statement.starts_with("WITH snippet AS (INSERT INTO code_snippet "));
+        assert!(statement.contains(" ON CONFLICT (slug) DO UPDATE SET "));
+        assert!(statement.ends_with(" WHERE code_snippet.modified < EXCLUDED.modified RETURNING id) DELETE FROM code_file WHERE code_snippet_id IN (SELECT id FROM snippet);\n"));
+    }
+
+    #[test]
+    fn upsert_with_files_replaces_the_files() {
+        let statement = insert_statement(&snippet("title"), &[file("main.py", b"x")], true);
+
+        assert!(statement.contains(" ON CONFLICT (slug) DO UPDATE SET "));
+        assert!(statement.ends_with(" RETURNING id), deleted AS (DELETE FROM code_file WHERE code_snippet_id IN (SELECT id FROM snippet)) INSERT INTO code_file (code_snippet_id, name, content) SELECT snippet.id, file.name, file.content FROM snippet, (VALUES ('main.py', '\\x78'::bytea)) AS file (name, content);\n"));
+    }
+}
@@ -0,0 +1,55 @@
+use crate::couchdb::CouchDb;
+use crate::error::Error;
+
+pub fn print_stats(couchdb: &CouchDb, client: &mut postgres::Client) -> Result<(), Error> {
+    let info = couchdb.get_info()?;
+    let snippet_count: i64 = client.query_one("SELECT count(*) FROM code_snippet", &[])?.get(0);
+    let file_count: i64 = client.query_one("SELECT count(*) FROM code_file", &[])?.get(0);
+
+    println!("CouchDB documents:  {} (including design documents)", info.doc_count);
+    println!("Postgres snippets:  {}", snippet_count);
+    println!("Postgres files:     {}", file_count);
+
+    if table_exists(client, "migration_checkpoint")? {
+        for row in client.query("SELECT name, last_id, rows_processed, updated FROM migration_checkpoint ORDER BY name", &[])? {
+            let name: String = row.get(0);
+            let last_id: String = row.get(1);
+            let rows_processed: i64 = row.get(2);
+            let updated: chrono::DateTime<chrono::Utc> = row.get(3);
+
+            println!("Checkpoint '{}':  after '{}', {} rows processed, updated {}", name, last_id, rows_processed, updated.to_rfc3339());
+        }
+    }
+
+    if table_exists(client, "migration_sync")? {
+        for row in client.query("SELECT name, since, changes_applied, updated FROM migration_sync ORDER BY name", &[])? {
+            let name: String = row.get(0);
+            let since: String = row.get(1);
+            let changes_applied: i64 = row.get(2);
+            let updated: chrono::DateTime<chrono::Utc> = row.get(3);
+
+            println!("Sync '{}':  at sequence {}, {} changes applied, updated {}", name, since, changes_applied, updated.to_rfc3339());
+        }
+    }
+
+    if table_exists(client, "migration_partition")? {
+        let counts = client.query("SELECT status, count(*) FROM migration_partition GROUP BY status ORDER BY status", &[])?
+            .iter()
+            .map(|row| format!("{} {}", row.get::<_, i64>(1), row.get::<_, String>(0)))
+            .collect::<Vec<_>>();
+
+        println!("Partitions:         {}", counts.join(", "));
+    }
+
+    if table_exists(client, "migration_failure")? {
+        let failure_count: i64 = client.query_one("SELECT count(*) FROM migration_failure", &[])?.get(0);
+        println!("Failed documents:   {}", failure_count);
+    }
+
+    Ok(())
+}
+
+fn table_exists(client: &mut postgres::Client, name: &str) -> Result<bool, Error> {
+    let exists = client.query_one("SELECT to_regclass($1) IS NOT NULL", &[&name])?.get(0);
+    Ok(exists)
+}
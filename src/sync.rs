@@ -0,0 +1,242 @@
+use crate::checkpoint::CHECKPOINT_NAME;
+use crate::couchdb::CouchChanges;
+use crate::couchdb::CouchDb;
+use crate::database;
+use crate::dead_letter::DeadLetter;
+use crate::error::Error;
+use crate::migrate;
+use crate::migrate::InsertResult;
+use crate::migrate::MigrateOptions;
+use crate::migrate::Profile;
+use crate::shutdown;
+use crate::sink::PostgresSink;
+use std::collections::HashMap;
+
+// The sequence is recorded before the bulk pass so changes made while it runs are picked up afterwards
+pub fn start(client: &mut postgres::Client, couchdb: &CouchDb, resume: bool) -> Result<String, Error> {
+    create_table(client)?;
+
+    match load_since(client)? {
+        Some(since) if resume => {
+            info!("Resuming sync from sequence {}", since);
+            Ok(since)
+        }
+
+        _ => {
+            let since = couchdb.get_update_seq()?;
+            save_since(client, &since, 0)?;
+            Ok(since)
+        }
+    }
+}
+
+pub fn create_table(client: &mut postgres::Client) -> Result<(), Error> {
+    client.batch_execute("
+        CREATE TABLE IF NOT EXISTS migration_sync (
+            name text PRIMARY KEY,
+            since text NOT NULL,
+            changes_applied bigint NOT NULL DEFAULT 0,
+            updated timestamptz NOT NULL DEFAULT now()
+        )
+    ")?;
+
+    Ok(())
+}
+
+fn load_since(client: &mut postgres::Client) -> Result<Option<String>, Error> {
+    let since = client.query_opt("SELECT since FROM migration_sync WHERE name = $1", &[&CHECKPOINT_NAME])?
+        .map(|row| row.get(0));
+
+    Ok(since)
+}
+
+fn save_since<C: postgres::GenericClient>(client: &mut C, since: &str, changes_applied: i64) -> Result<(), Error> {
+    client.execute("
+        INSERT INTO migration_sync (name, since, changes_applied, updated) VALUES ($1, $2, $3, now())
+        ON CONFLICT (name) DO UPDATE SET since = EXCLUDED.since, changes_applied = migration_sync.changes_applied + EXCLUDED.changes_applied, updated = EXCLUDED.updated
+    ", &[&CHECKPOINT_NAME, &since, &changes_applied])?;
+
+    Ok(())
+}
+
+pub fn sync_loop(sink: &mut PostgresSink, couchdb: &CouchDb, mut since: String, continuous: bool) -> Result<(), Error> {
+    let (profiles, options, dead_letter) = (sink.profiles, sink.options, sink.dead_letter);
+
+    loop {
+        if shutdown::requested() {
+            info!("Sync stopped at sequence {}", since);
+            return Ok(());
+        }
+
+        let changes = couchdb.get_changes(&since, options.batch_size, continuous)?;
+
+        if changes.results.is_empty() && !continuous {
+            info!("Sync caught up at sequence {}", since);
+            return Ok(());
+        }
+
+        let last_seq = changes.last_seq();
+
+        database::with_reconnect(sink.client, sink.connect,
+            |client| Ok((load_since(client)?.as_deref() == Some(last_seq.as_str())).then_some(())),
+            |client| apply_changes(client, &changes, &last_seq, profiles, options, dead_letter))?;
+
+        since = last_seq;
+    }
+}
+
+fn apply_changes(client: &mut postgres::Client, changes: &CouchChanges, last_seq: &str, profiles: &HashMap<String, Profile>, options: &MigrateOptions, dead_letter: &DeadLetter) -> Result<(), Error> {
+    let statements = migrate::prepare_statements(client, true)?;
+    let delete_files = client.prepare("DELETE FROM code_file WHERE code_snippet_id IN (SELECT id FROM code_snippet WHERE slug = $1)")?;
+    let delete_snippet = client.prepare("DELETE FROM code_snippet WHERE slug = $1")?;
+
+    let mut transaction = client.transaction()?;
+    let mut failures = dead_letter.batch();
+
+    let mut inserted_count = 0;
+    let mut updated_count = 0;
+    let mut deleted_count = 0;
+
+    for change in &changes.results {
+        if change.id.starts_with("_design/") {
+            continue;
+        }
+
+        if change.deleted {
+            transaction.execute(&delete_files, &[&change.id])?;
+            deleted_count += transaction.execute(&delete_snippet, &[&change.id])?;
+            continue;
+        }
+
+        let doc = change.doc.clone().unwrap_or(serde_json::Value::Null);
+
+        match migrate::insert_document_with_policy(&mut transaction, &statements, profiles, &change.id, &doc, options.error_policy, &mut failures)? {
+            Some(InsertResult::Inserted) => inserted_count += 1,
+            Some(InsertResult::Updated) => updated_count += 1,
+            Some(InsertResult::Unchanged) | None => (),
+        }
+    }
+
+    save_since(&mut transaction, last_seq, changes.results.len() as i64)?;
+    transaction.commit()?;
+    failures.commit()?;
+
+    if !changes.results.is_empty() {
+        info!("Synced {} changes (inserted {}, updated {}, deleted {}), now at sequence {}", changes.results.len(), inserted_count, updated_count, deleted_count, last_seq);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dead_letter::DeadLetter;
+    use crate::fake_couchdb::FakeCouchDb;
+    use crate::fake_couchdb::Route;
+    use std::env;
+
+    fn fake_couchdb() -> FakeCouchDb {
+        FakeCouchDb::start(vec![
+            Route::new("/snippets", &[], include_str!("../tests/fixtures/changes/info.json")),
+            Route::new("/snippets/_changes", &[("since", "0-g1AAAAB")], include_str!("../tests/fixtures/changes/since_0.json")),
+            Route::new("/snippets/_changes", &[("since", "2-g1AAAAB2")], include_str!("../tests/fixtures/changes/since_2.json")),
+            Route::new("/snippets/_changes", &[("since", "5-g1AAAAB5")], include_str!("../tests/fixtures/changes/since_5.json")),
+        ])
+    }
+
+    // The tables are created in their own schema of the database at TEST_DATABASE_URL
+    fn connect(url: &str) -> Result<postgres::Client, Error> {
+        let mut config = url.parse::<postgres::Config>()?;
+        config.options("-c search_path=glot_sync_test");
+
+        Ok(config.connect(postgres::NoTls)?)
+    }
+
+    fn options() -> MigrateOptions {
+        MigrateOptions{
+            batch_size: 100,
+            upsert: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn reads_changes_with_deletions_and_last_seq() {
+        let couchdb = fake_couchdb().couchdb();
+
+        let changes = couchdb.get_changes("0-g1AAAAB", 100, false).unwrap();
+        assert_eq!(changes.results.iter().map(|change| change.id.as_str()).collect::<Vec<_>>(), ["abc123", "def456"]);
+        assert_eq!(changes.last_seq(), "2-g1AAAAB2");
+
+        let changes = couchdb.get_changes("2-g1AAAAB2", 100, false).unwrap();
+        assert_eq!(changes.results.iter().map(|change| change.deleted).collect::<Vec<_>>(), [false, true, false]);
+        assert_eq!(changes.last_seq(), "5-g1AAAAB5");
+
+        let changes = couchdb.get_changes("5-g1AAAAB5", 100, false).unwrap();
+        assert!(changes.results.is_empty());
+    }
+
+    #[test]
+    fn applies_creates_updates_and_deletions() {
+        let url = match env::var("TEST_DATABASE_URL") {
+            Ok(url) => url,
+            Err(_) => {
+                eprintln!("TEST_DATABASE_URL is not set, skipping");
+                return;
+            }
+        };
+
+        let mut client = postgres::Client::connect(&url, postgres::NoTls).unwrap();
+        client.batch_execute("
+            DROP SCHEMA IF EXISTS glot_sync_test CASCADE;
+            CREATE SCHEMA glot_sync_test;
+            CREATE TABLE glot_sync_test.profile (user_id bigint PRIMARY KEY, snippets_api_id varchar NOT NULL, username varchar NOT NULL);
+            CREATE TABLE glot_sync_test.code_snippet (id bigserial PRIMARY KEY, slug varchar NOT NULL UNIQUE, language varchar NOT NULL, title varchar NOT NULL, public boolean NOT NULL, user_id bigint NULL, created timestamptz NOT NULL, modified timestamptz NOT NULL);
+            CREATE TABLE glot_sync_test.code_file (id bigserial PRIMARY KEY, code_snippet_id bigint NOT NULL REFERENCES glot_sync_test.code_snippet(id), name varchar NOT NULL, content bytea NOT NULL);
+            INSERT INTO glot_sync_test.profile VALUES (1, 'owner-a', 'alice');
+        ").unwrap();
+
+        let fake = fake_couchdb();
+        let couchdb = fake.couchdb();
+        let mut client = connect(&url).unwrap();
+        let profiles = migrate::load_profiles(&mut client).unwrap();
+        let options = options();
+        let dead_letter = DeadLetter::new(None, false).unwrap();
+        let connect = || connect(&url);
+
+        let since = start(&mut client, &couchdb, false).unwrap();
+        assert_eq!(since, "0-g1AAAAB");
+
+        let mut sink = PostgresSink{ client: &mut client, connect: &connect, profiles: &profiles, options: &options, dead_letter: &dead_letter };
+        sync_loop(&mut sink, &couchdb, since, false).unwrap();
+
+        let snippets = client.query("SELECT slug, title, user_id FROM code_snippet ORDER BY slug", &[]).unwrap()
+            .iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2)))
+            .collect::<Vec<(String, String, Option<i64>)>>();
+        assert_eq!(snippets, [("abc123".to_string(), "Hello again".to_string(), Some(1))]);
+
+        let files = client.query("SELECT name, content FROM code_file ORDER BY name", &[]).unwrap()
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect::<Vec<(String, Vec<u8>)>>();
+        assert_eq!(files, [("main.py".to_string(), b"print(2)".to_vec()), ("util.py".to_string(), b"x = 1".to_vec())]);
+
+        let row = client.query_one("SELECT since, changes_applied FROM migration_sync", &[]).unwrap();
+        assert_eq!(row.get::<_, String>(0), "5-g1AAAAB5");
+        assert_eq!(row.get::<_, i64>(1), 5);
+
+        // Resuming continues after the stored sequence instead of the current one
+        let since = start(&mut client, &couchdb, true).unwrap();
+        assert_eq!(since, "5-g1AAAAB5");
+
+        let mut sink = PostgresSink{ client: &mut client, connect: &connect, profiles: &profiles, options: &options, dead_letter: &dead_letter };
+        sync_loop(&mut sink, &couchdb, since, false).unwrap();
+
+        let row = client.query_one("SELECT count(*) FROM code_snippet", &[]).unwrap();
+        assert_eq!(row.get::<_, i64>(0), 1);
+
+        client.batch_execute("DROP SCHEMA glot_sync_test CASCADE").unwrap();
+    }
+}
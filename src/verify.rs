@@ -0,0 +1,305 @@
+use crate::couchdb::CouchRow;
+use crate::error::Error;
+use crate::migrate;
+use crate::migrate::CodeFile;
+use crate::migrate::CodeSnippet;
+use crate::migrate::Profile;
+use crate::source::SnippetSource;
+use serde_json::json;
+use sha2::Digest;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    documents: usize,
+    matching: usize,
+    missing: usize,
+    extra: usize,
+    mismatched: usize,
+    invalid: usize,
+    differences: usize,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Difference<'a> {
+    slug: &'a str,
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<String>,
+    couchdb: serde_json::Value,
+    postgres: serde_json::Value,
+}
+
+struct PostgresSnippet {
+    id: i64,
+    snippet: CodeSnippet,
+    files: Vec<PostgresFile>,
+}
+
+struct PostgresFile {
+    name: String,
+    sha256: String,
+}
+
+pub struct Verifier<'a> {
+    source: &'a dyn SnippetSource,
+    client: &'a mut postgres::Client,
+    profiles: &'a HashMap<String, Profile>,
+    output: Option<(String, fs::File)>,
+    report: VerifyReport,
+}
+
+impl<'a> Verifier<'a> {
+    pub fn new(source: &'a dyn SnippetSource, client: &'a mut postgres::Client, profiles: &'a HashMap<String, Profile>, output_path: Option<&str>) -> Result<Self, Error> {
+        let output = match output_path {
+            Some(path) => {
+                let file = fs::File::create(path)
+                    .map_err(|error| Error::Io{ path: path.to_string(), error })?;
+
+                Some((path.to_string(), file))
+            }
+
+            None => None,
+        };
+
+        Ok(Verifier{
+            source,
+            client,
+            profiles,
+            output,
+            report: VerifyReport::default(),
+        })
+    }
+
+    // Walks CouchDB and Postgres in id order, every page of documents is compared with the snippets in the same slug range
+    pub fn run(mut self, batch_size: u64) -> Result<VerifyReport, Error> {
+        let mut start_key: Option<String> = None;
+
+        loop {
+            let documents = self.source.get_documents(start_key.clone(), None, batch_size)?;
+
+            info!("Verified {} of {}", self.report.documents, documents.total_rows);
+
+            let snippets = self.load_snippets(start_key.as_deref(), documents.last_key.as_deref())?;
+
+            self.compare_page(&documents.rows, snippets)?;
+
+            match documents.last_key {
+                Some(end_key) => start_key = Some(end_key),
+                None => return Ok(self.report),
+            }
+        }
+    }
+
+    fn load_snippets(&mut self, after: Option<&str>, until: Option<&str>) -> Result<HashMap<String, PostgresSnippet>, Error> {
+        // The C collation matches the raw ordering CouchDB uses for document ids
+        let rows = self.client.query("
+            SELECT id, slug, language, title, public, user_id, created, modified FROM code_snippet
+            WHERE ($1::text IS NULL OR slug COLLATE \"C\" > $1) AND ($2::text IS NULL OR slug COLLATE \"C\" <= $2)
+        ", &[&after, &until])?;
+
+        let mut snippets = rows.iter()
+            .map(|row| {
+                let created: chrono::DateTime<chrono::Utc> = row.get(6);
+                let modified: chrono::DateTime<chrono::Utc> = row.get(7);
+
+                let snippet = PostgresSnippet{
+                    id: row.get(0),
+                    snippet: CodeSnippet{
+                        slug: row.get(1),
+                        language: row.get(2),
+                        title: row.get(3),
+                        public: row.get(4),
+                        user_id: row.get(5),
+                        created: created.into(),
+                        modified: modified.into(),
+                    },
+                    files: Vec::new(),
+                };
+
+                (snippet.id, snippet)
+            })
+            .collect::<HashMap<i64, PostgresSnippet>>();
+
+        let ids = snippets.keys().copied().collect::<Vec<i64>>();
+
+        let file_rows = self.client.query("
+            SELECT code_snippet_id, name, encode(sha256(content), 'hex') FROM code_file
+            WHERE code_snippet_id = ANY($1)
+            ORDER BY code_snippet_id, id
+        ", &[&ids])?;
+
+        for row in &file_rows {
+            let snippet_id: i64 = row.get(0);
+
+            if let Some(snippet) = snippets.get_mut(&snippet_id) {
+                snippet.files.push(PostgresFile{
+                    name: row.get(1),
+                    sha256: row.get(2),
+                });
+            }
+        }
+
+        Ok(snippets.into_values()
+            .map(|snippet| (snippet.snippet.slug.clone(), snippet))
+            .collect())
+    }
+
+    fn compare_page(&mut self, rows: &[CouchRow], mut snippets: HashMap<String, PostgresSnippet>) -> Result<(), Error> {
+        // Pages are compared by slug range, which only works when the documents come in id order like from _all_docs
+        if let Some(row) = rows.windows(2).find(|pair| pair[0].id >= pair[1].id).map(|pair| &pair[1]) {
+            return Err(Error::UnsortedDocuments{ id: row.id.clone() });
+        }
+
+        for row in rows {
+            self.report.documents += 1;
+
+            match snippets.remove(&row.id) {
+                Some(snippet) => self.compare_document(row, &snippet)?,
+
+                None => {
+                    self.report.missing += 1;
+                    self.record(&Difference{ slug: &row.id, kind: "missing", field: None, couchdb: json!(true), postgres: json!(false) })?;
+                }
+            }
+        }
+
+        let mut extra = snippets.into_keys().collect::<Vec<String>>();
+        extra.sort();
+
+        for slug in &extra {
+            self.report.extra += 1;
+            self.record(&Difference{ slug, kind: "extra", field: None, couchdb: json!(false), postgres: json!(true) })?;
+        }
+
+        Ok(())
+    }
+
+    fn compare_document(&mut self, row: &CouchRow, actual: &PostgresSnippet) -> Result<(), Error> {
+        let converted = migrate::parse_document(&row.doc)
+            .and_then(|doc| migrate::convert_document(&doc, self.profiles));
+
+        let (expected, expected_files) = match converted {
+            Ok(converted) => converted,
+
+            Err(error) => {
+                self.report.invalid += 1;
+                return self.record(&Difference{ slug: &row.id, kind: "invalid_document", field: None, couchdb: json!(error.to_string()), postgres: json!(true) });
+            }
+        };
+
+        let mut differences = compare_snippet(&expected, &actual.snippet);
+        differences.extend(compare_files(&expected_files, &actual.files));
+
+        if differences.is_empty() {
+            self.report.matching += 1;
+            return Ok(());
+        }
+
+        self.report.mismatched += 1;
+
+        for (field, couchdb, postgres) in differences {
+            self.record(&Difference{ slug: &row.id, kind: "mismatch", field: Some(field), couchdb, postgres })?;
+        }
+
+        Ok(())
+    }
+
+    fn record(&mut self, difference: &Difference) -> Result<(), Error> {
+        self.report.differences += 1;
+
+        match &difference.field {
+            Some(field) => debug!("{} '{}' {}: {} != {}", difference.kind, difference.slug, field, difference.couchdb, difference.postgres),
+            None => debug!("{} '{}'", difference.kind, difference.slug),
+        }
+
+        if let Some((path, file)) = &mut self.output {
+            let mut line = serde_json::to_vec(difference)
+                .map_err(|error| Error::Io{ path: path.clone(), error: error.into() })?;
+            line.push(b'\n');
+
+            file.write_all(&line)
+                .map_err(|error| Error::Io{ path: path.clone(), error })?;
+        }
+
+        Ok(())
+    }
+}
+
+fn compare_snippet(expected: &CodeSnippet, actual: &CodeSnippet) -> Vec<(String, serde_json::Value, serde_json::Value)> {
+    let mut differences = Vec::new();
+
+    if expected.title != actual.title {
+        differences.push(("title".to_string(), json!(expected.title), json!(actual.title)));
+    }
+
+    if expected.language != actual.language {
+        differences.push(("language".to_string(), json!(expected.language), json!(actual.language)));
+    }
+
+    if expected.public != actual.public {
+        differences.push(("public".to_string(), json!(expected.public), json!(actual.public)));
+    }
+
+    if expected.user_id != actual.user_id {
+        differences.push(("user_id".to_string(), json!(expected.user_id), json!(actual.user_id)));
+    }
+
+    // Compared as instants, Postgres doesn't keep the original offset
+    if expected.created != actual.created {
+        differences.push(("created".to_string(), json!(expected.created.to_rfc3339()), json!(actual.created.to_rfc3339())));
+    }
+
+    if expected.modified != actual.modified {
+        differences.push(("modified".to_string(), json!(expected.modified.to_rfc3339()), json!(actual.modified.to_rfc3339())));
+    }
+
+    differences
+}
+
+fn compare_files(expected: &[CodeFile], actual: &[PostgresFile]) -> Vec<(String, serde_json::Value, serde_json::Value)> {
+    let mut differences = Vec::new();
+
+    if expected.len() != actual.len() {
+        differences.push(("files".to_string(), json!(expected.len()), json!(actual.len())));
+    }
+
+    for (index, (expected, actual)) in expected.iter().zip(actual).enumerate() {
+        if expected.name != actual.name {
+            differences.push((format!("files[{}].name", index), json!(expected.name), json!(actual.name)));
+        }
+
+        let expected_sha256 = sha256_hex(&expected.content);
+
+        if expected_sha256 != actual.sha256 {
+            differences.push((format!("files[{}].sha256", index), json!(expected_sha256), json!(actual.sha256)));
+        }
+    }
+
+    differences
+}
+
+fn sha256_hex(content: &[u8]) -> String {
+    sha2::Sha256::digest(content).iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+impl VerifyReport {
+    pub fn differences(&self) -> usize {
+        self.differences
+    }
+}
+
+pub fn print_report(report: &VerifyReport) {
+    println!();
+    println!("Verification summary");
+    println!("  Documents:          {}", report.documents);
+    println!("  Matching:           {}", report.matching);
+    println!("  Missing snippets:   {}", report.missing);
+    println!("  Extra snippets:     {}", report.extra);
+    println!("  Mismatched:         {}", report.mismatched);
+    println!("  Invalid documents:  {}", report.invalid);
+}